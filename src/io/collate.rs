@@ -0,0 +1,169 @@
+/*
+Copyright 2017 the superlearn authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use densearray::prelude::*;
+
+use std::ops::{Deref};
+
+/// Stacks a minibatch of items into one contiguous batch value.
+///
+/// Arrays are stacked along a new outermost dimension, so that a batch of
+/// `(width, height, channels)` images becomes a `(width, height, channels,
+/// batch size)` array (NCHW in row-major terms).
+pub trait Collate: Sized {
+  type Batch;
+
+  fn collate(items: Vec<Self>) -> Self::Batch;
+}
+
+impl Collate for u32 {
+  type Batch = Vec<u32>;
+
+  fn collate(items: Vec<u32>) -> Vec<u32> {
+    items
+  }
+}
+
+impl Collate for usize {
+  type Batch = Vec<usize>;
+
+  fn collate(items: Vec<usize>) -> Vec<usize> {
+    items
+  }
+}
+
+impl Collate for f32 {
+  type Batch = Vec<f32>;
+
+  fn collate(items: Vec<f32>) -> Vec<f32> {
+    items
+  }
+}
+
+impl<A, B> Collate for (A, B) where A: Collate, B: Collate {
+  type Batch = (A::Batch, B::Batch);
+
+  fn collate(items: Vec<(A, B)>) -> (A::Batch, B::Batch) {
+    let mut a_items = Vec::with_capacity(items.len());
+    let mut b_items = Vec::with_capacity(items.len());
+    for (a, b) in items {
+      a_items.push(a);
+      b_items.push(b);
+    }
+    (A::collate(a_items), B::collate(b_items))
+  }
+}
+
+impl<T, S> Collate for Array2d<T, S> where T: Copy, S: Deref<Target=[T]> {
+  type Batch = Array3d<T, Vec<T>>;
+
+  fn collate(items: Vec<Array2d<T, S>>) -> Array3d<T, Vec<T>> {
+    assert!(!items.is_empty());
+    let dim = items[0].dim();
+    let mut buf = Vec::with_capacity(dim.flat_len() * items.len());
+    for item in items.iter() {
+      assert_eq!(dim, item.dim());
+      buf.extend_from_slice(item.as_slice());
+    }
+    Array3d::from_storage((dim.0, dim.1, items.len()), buf)
+  }
+}
+
+impl<T, S> Collate for Array3d<T, S> where T: Copy, S: Deref<Target=[T]> {
+  type Batch = Array4d<T, Vec<T>>;
+
+  fn collate(items: Vec<Array3d<T, S>>) -> Array4d<T, Vec<T>> {
+    assert!(!items.is_empty());
+    let dim = items[0].dim();
+    let mut buf = Vec::with_capacity(dim.flat_len() * items.len());
+    for item in items.iter() {
+      assert_eq!(dim, item.dim());
+      buf.extend_from_slice(item.as_slice());
+    }
+    Array4d::from_storage((dim.0, dim.1, dim.2, items.len()), buf)
+  }
+}
+
+/// What to do with the final minibatch when the inner iterator runs out
+/// before it is full.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LastBatch {
+  /// Discard the partial minibatch.
+  Drop,
+  /// Fill the minibatch up to size by repeating its items.
+  Pad,
+  /// Emit a minibatch with fewer items.
+  Short,
+}
+
+pub fn batch<Inner>(batch_sz: usize, last: LastBatch, inner: Inner) -> BatchIter<Inner> where Inner: Iterator, Inner::Item: Collate + Clone {
+  assert!(batch_sz > 0);
+  BatchIter{
+    batch_sz: batch_sz,
+    last:     last,
+    closed:   false,
+    inner:    inner,
+  }
+}
+
+pub struct BatchIter<Inner> {
+  batch_sz: usize,
+  last:     LastBatch,
+  closed:   bool,
+  inner:    Inner,
+}
+
+impl<Inner> Iterator for BatchIter<Inner> where Inner: Iterator, Inner::Item: Collate + Clone {
+  /// The number of real (non-padding) items, and the collated minibatch.
+  type Item = (usize, <Inner::Item as Collate>::Batch);
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.closed {
+      return None;
+    }
+    let mut items = Vec::with_capacity(self.batch_sz);
+    while items.len() < self.batch_sz {
+      match self.inner.next() {
+        None => {
+          self.closed = true;
+          break;
+        }
+        Some(item) => {
+          items.push(item);
+        }
+      }
+    }
+    let count = items.len();
+    if count == 0 {
+      return None;
+    }
+    if count < self.batch_sz {
+      match self.last {
+        LastBatch::Drop => {
+          return None;
+        }
+        LastBatch::Pad => {
+          while items.len() < self.batch_sz {
+            let item = items[items.len() % count].clone();
+            items.push(item);
+          }
+        }
+        LastBatch::Short => {}
+      }
+    }
+    Some((count, <Inner::Item as Collate>::collate(items)))
+  }
+}
@@ -15,18 +15,87 @@ limitations under the License.
 */
 
 use io::*;
+use io::formats::pickle::{PickleValue, load_pickle_file};
 
 use densearray::prelude::*;
 use sharedmem::{MemoryMap, SharedMem};
 
-use std::fs::{File};
+use std::io::{BufRead, BufReader};
 use std::marker::{PhantomData};
 use std::path::{PathBuf};
+use std::rc::{Rc};
+
+pub const CIFAR10_LABEL_NAMES: &'static [&'static str] = &[
+  "airplane", "automobile", "bird", "cat", "deer",
+  "dog", "frog", "horse", "ship", "truck",
+];
+
+pub const CIFAR100_COARSE_LABEL_NAMES: &'static [&'static str] = &[
+  "aquatic_mammals", "fish", "flowers",
+  "food_containers", "fruit_and_vegetables", "household_electrical_devices",
+  "household_furniture", "insects", "large_carnivores",
+  "large_man-made_outdoor_things", "large_natural_outdoor_scenes", "large_omnivores_and_herbivores",
+  "medium_mammals", "non-insect_invertebrates", "people",
+  "reptiles", "small_mammals", "trees",
+  "vehicles_1", "vehicles_2",
+];
+
+pub const CIFAR100_FINE_LABEL_NAMES: &'static [&'static str] = &[
+  "apple", "aquarium_fish", "baby", "bear", "beaver", "bed",
+  "bee", "beetle", "bicycle", "bottle", "bowl", "boy",
+  "bridge", "bus", "butterfly", "camel", "can", "castle",
+  "caterpillar", "cattle", "chair", "chimpanzee", "clock", "cloud",
+  "cockroach", "couch", "crab", "crocodile", "cup", "dinosaur",
+  "dolphin", "elephant", "flatfish", "forest", "fox", "girl",
+  "hamster", "house", "kangaroo", "keyboard", "lamp", "lawn_mower",
+  "leopard", "lion", "lizard", "lobster", "man", "maple_tree",
+  "motorcycle", "mountain", "mouse", "mushroom", "oak_tree", "orange",
+  "orchid", "otter", "palm_tree", "pear", "pickup_truck", "pine_tree",
+  "plain", "plate", "poppy", "porcupine", "possum", "rabbit",
+  "raccoon", "ray", "road", "rocket", "rose", "sea",
+  "seal", "shark", "shrew", "skunk", "skyscraper", "snail",
+  "snake", "spider", "squirrel", "streetcar", "sunflower", "sweet_pepper",
+  "table", "tank", "telephone", "television", "tiger", "tractor",
+  "train", "trout", "tulip", "turtle", "wardrobe", "whale",
+  "willow_tree", "wolf", "woman", "worm",
+];
+
+/// Loads a class name table with one name per line, as in `batches.meta.txt`,
+/// `coarse_label_names.txt` or `fine_label_names.txt`. Blank lines are
+/// skipped.
+pub fn load_label_names(path: PathBuf) -> Vec<String> {
+  match try_load_label_names(path) {
+    Ok(names) => names,
+    Err(e) => panic!("failed to load cifar label names: {}", e),
+  }
+}
+
+pub fn try_load_label_names(path: PathBuf) -> Result<Vec<String>, Error> {
+  let file = open_file(&path)?;
+  let reader = BufReader::new(file);
+  let mut names = vec![];
+  for line in reader.lines() {
+    let line = line?;
+    let name = line.trim();
+    if !name.is_empty() {
+      names.push(name.to_owned());
+    }
+  }
+  Ok(names)
+}
 
 pub trait KrizhevskyCifarFlavor {
   fn item_size() -> usize;
+  /// Offset of the (fine) label byte.
   fn label10_offset() -> usize;
+  /// Offset of the coarse superclass label byte, if the flavor has one.
+  fn coarse_label_offset() -> Option<usize> { None }
   fn frame_offset() -> usize;
+  fn label_names() -> &'static [&'static str] { &[] }
+  fn coarse_label_names() -> &'static [&'static str] { &[] }
+  /// Key of the (fine) label list in the python pickle batches.
+  fn pickle_label_key() -> &'static str { "labels" }
+  fn pickle_coarse_label_key() -> Option<&'static str> { None }
 }
 
 pub struct KrizhevskyCifar10Flavor;
@@ -35,6 +104,7 @@ impl KrizhevskyCifarFlavor for KrizhevskyCifar10Flavor {
   fn item_size() -> usize { 3073 }
   fn label10_offset() -> usize { 0 }
   fn frame_offset() -> usize { 1 }
+  fn label_names() -> &'static [&'static str] { CIFAR10_LABEL_NAMES }
 }
 
 pub struct KrizhevskyCifar100Flavor;
@@ -42,17 +112,25 @@ pub struct KrizhevskyCifar100Flavor;
 impl KrizhevskyCifarFlavor for KrizhevskyCifar100Flavor {
   fn item_size() -> usize { 3074 }
   fn label10_offset() -> usize { 1 }
+  fn coarse_label_offset() -> Option<usize> { Some(0) }
   fn frame_offset() -> usize { 2 }
+  fn label_names() -> &'static [&'static str] { CIFAR100_FINE_LABEL_NAMES }
+  fn coarse_label_names() -> &'static [&'static str] { CIFAR100_COARSE_LABEL_NAMES }
+  fn pickle_label_key() -> &'static str { "fine_labels" }
+  fn pickle_coarse_label_key() -> Option<&'static str> { Some("coarse_labels") }
 }
 
 pub type Cifar10Data  = KrizhevskyCifarData<KrizhevskyCifar10Flavor>;
 pub type Cifar100Data = KrizhevskyCifarData<KrizhevskyCifar100Flavor>;
+pub type Cifar10PickleData  = KrizhevskyCifarPickleData<KrizhevskyCifar10Flavor>;
+pub type Cifar100PickleData = KrizhevskyCifarPickleData<KrizhevskyCifar100Flavor>;
 
 #[derive(Clone)]
 pub struct KrizhevskyCifarData<Flavor> {
   len:      usize,
   item_sz:  usize,
   label_p:  usize,
+  coarse:   bool,
   frame_p:  usize,
   frame_d:  (usize, usize, usize),
   data_m:   SharedMem<u8>,
@@ -61,28 +139,90 @@ pub struct KrizhevskyCifarData<Flavor> {
 
 impl<Flavor> KrizhevskyCifarData<Flavor> where Flavor: KrizhevskyCifarFlavor {
   pub fn open(data_path: PathBuf) -> KrizhevskyCifarData<Flavor> {
-    let data_file = File::open(&data_path).unwrap();
-    let file_meta = data_file.metadata().unwrap();
+    match Self::try_open(data_path) {
+      Ok(data) => data,
+      Err(e) => panic!("failed to open cifar batch file: {}", e),
+    }
+  }
+
+  /// Opens several batch files (e.g. `data_batch_{1..5}.bin`) as one source.
+  pub fn open_batches(data_paths: Vec<PathBuf>) -> ConcatData<KrizhevskyCifarData<Flavor>> {
+    let mut batches = Vec::with_capacity(data_paths.len());
+    for data_path in data_paths {
+      batches.push(Self::open(data_path));
+    }
+    concat(batches)
+  }
+
+  pub fn try_open_batches(data_paths: Vec<PathBuf>) -> Result<ConcatData<KrizhevskyCifarData<Flavor>>, Error> {
+    let mut batches = Vec::with_capacity(data_paths.len());
+    for data_path in data_paths {
+      batches.push(Self::try_open(data_path)?);
+    }
+    Ok(concat(batches))
+  }
+
+  pub fn try_open(data_path: PathBuf) -> Result<KrizhevskyCifarData<Flavor>, Error> {
+    let data_file = open_file(&data_path)?;
+    let file_meta = data_file.metadata()?;
     let file_sz = file_meta.len() as usize;
     let item_sz = <Flavor as KrizhevskyCifarFlavor>::item_size();
-    assert_eq!(0, file_sz % item_sz);
+    if file_sz % item_sz != 0 {
+      return Err(Error::Format(format!("cifar: file size {} is not a multiple of item size {}", file_sz, item_sz)));
+    }
     let len = file_sz / item_sz;
     let label_p = <Flavor as KrizhevskyCifarFlavor>::label10_offset();
     let frame_p = <Flavor as KrizhevskyCifarFlavor>::frame_offset();
     let buf = match MemoryMap::open_with_offset(data_file, 0, file_sz) {
       Ok(buf) => buf,
-      Err(e) => panic!("failed to mmap cifar batch file: {:?}", e),
+      Err(e) => return Err(Error::Mmap(format!("{:?}", e))),
     };
-    KrizhevskyCifarData{
+    Ok(KrizhevskyCifarData{
       len:      len,
       item_sz:  item_sz,
       label_p:  label_p,
+      coarse:   false,
       frame_p:  frame_p,
       frame_d:  (32, 32, 3),
       data_m:   SharedMem::new(buf),
       _marker:  PhantomData,
+    })
+  }
+
+  /// Yields the coarse superclass label instead of the fine label.
+  pub fn coarse_labels(mut self) -> KrizhevskyCifarData<Flavor> {
+    match <Flavor as KrizhevskyCifarFlavor>::coarse_label_offset() {
+      None => panic!("cifar: this flavor has no coarse labels"),
+      Some(coarse_p) => self.label_p = coarse_p,
+    }
+    self.coarse = true;
+    self
+  }
+
+  /// Yields both labels as `(fine, coarse)`.
+  pub fn fine_and_coarse_labels(self) -> KrizhevskyCifarFineCoarseData<Flavor> {
+    let coarse_p = match <Flavor as KrizhevskyCifarFlavor>::coarse_label_offset() {
+      None => panic!("cifar: this flavor has no coarse labels"),
+      Some(coarse_p) => coarse_p,
+    };
+    KrizhevskyCifarFineCoarseData{
+      coarse_p: coarse_p,
+      inner:    self,
+    }
+  }
+
+  /// The names of the labels currently yielded, coarse or fine.
+  pub fn label_names(&self) -> &'static [&'static str] {
+    if self.coarse {
+      <Flavor as KrizhevskyCifarFlavor>::coarse_label_names()
+    } else {
+      <Flavor as KrizhevskyCifarFlavor>::label_names()
     }
   }
+
+  pub fn coarse_label_names(&self) -> &'static [&'static str] {
+    <Flavor as KrizhevskyCifarFlavor>::coarse_label_names()
+  }
 }
 
 impl<Flavor> IndexedData for KrizhevskyCifarData<Flavor> where Flavor: KrizhevskyCifarFlavor {
@@ -98,3 +238,194 @@ impl<Flavor> IndexedData for KrizhevskyCifarData<Flavor> where Flavor: Krizhevsk
     (Array3d::from_storage(self.frame_d, frame_buf), label)
   }
 }
+
+impl_stateless!(KrizhevskyCifarData<Flavor>);
+
+#[derive(Clone)]
+pub struct KrizhevskyCifarFineCoarseData<Flavor> {
+  coarse_p: usize,
+  inner:    KrizhevskyCifarData<Flavor>,
+}
+
+impl<Flavor> IndexedData for KrizhevskyCifarFineCoarseData<Flavor> where Flavor: KrizhevskyCifarFlavor {
+  type Item = (Array3d<u8, SharedMem<u8>>, (u32, u32));
+
+  fn len(&self) -> usize {
+    self.inner.len()
+  }
+
+  fn get(&mut self, idx: usize) -> Self::Item {
+    let (frame, _) = self.inner.get(idx);
+    let item_p = idx * self.inner.item_sz;
+    let fine_label = self.inner.data_m[item_p + <Flavor as KrizhevskyCifarFlavor>::label10_offset()] as u32;
+    let coarse_label = self.inner.data_m[item_p + self.coarse_p] as u32;
+    (frame, (fine_label, coarse_label))
+  }
+}
+
+impl_stateless!(KrizhevskyCifarFineCoarseData<Flavor>);
+
+const PICKLE_FRAME_SZ: usize = 3072;
+
+fn pickle_labels(batch: &PickleValue, key: &str, len: usize) -> Result<Vec<u32>, Error> {
+  let list = match batch.get(key).and_then(|labels| labels.as_list()) {
+    None => return Err(Error::Format(format!("cifar pickle: missing '{}' list", key))),
+    Some(list) => list,
+  };
+  if list.len() != len {
+    return Err(Error::Format(format!("cifar pickle: '{}' has {} entries, expected {}", key, list.len(), len)));
+  }
+  let mut labels = Vec::with_capacity(len);
+  for (idx, label) in list.iter().enumerate() {
+    match label.as_i64() {
+      Some(label) if label >= 0 && label < 256 => labels.push(label as u32),
+      _ => return Err(Error::Corrupt(idx, format!("cifar pickle: bad '{}' entry", key))),
+    }
+  }
+  Ok(labels)
+}
+
+/// CIFAR batches in the python pickle format (e.g. the extracted contents of
+/// `cifar-10-python.tar.gz`), yielding the same items as
+/// `KrizhevskyCifarData`. Each batch is decoded into memory on open.
+#[derive(Clone)]
+pub struct KrizhevskyCifarPickleData<Flavor> {
+  len:      usize,
+  frame_d:  (usize, usize, usize),
+  labels:   Vec<u32>,
+  coarse_labels:    Option<Vec<u32>>,
+  coarse:   bool,
+  data_m:   SharedMem<u8>,
+  _marker:  PhantomData<fn (Flavor)>,
+}
+
+impl<Flavor> KrizhevskyCifarPickleData<Flavor> where Flavor: KrizhevskyCifarFlavor {
+  pub fn open(data_path: PathBuf) -> KrizhevskyCifarPickleData<Flavor> {
+    match Self::try_open(data_path) {
+      Ok(data) => data,
+      Err(e) => panic!("failed to open cifar pickle batch: {}", e),
+    }
+  }
+
+  pub fn open_batches(data_paths: Vec<PathBuf>) -> ConcatData<KrizhevskyCifarPickleData<Flavor>> {
+    let mut batches = Vec::with_capacity(data_paths.len());
+    for data_path in data_paths {
+      batches.push(Self::open(data_path));
+    }
+    concat(batches)
+  }
+
+  pub fn try_open_batches(data_paths: Vec<PathBuf>) -> Result<ConcatData<KrizhevskyCifarPickleData<Flavor>>, Error> {
+    let mut batches = Vec::with_capacity(data_paths.len());
+    for data_path in data_paths {
+      batches.push(Self::try_open(data_path)?);
+    }
+    Ok(concat(batches))
+  }
+
+  pub fn try_open(data_path: PathBuf) -> Result<KrizhevskyCifarPickleData<Flavor>, Error> {
+    let batch = load_pickle_file(data_path)?;
+    let (shape, dtype, data) = match batch.get("data").and_then(|data| data.as_ndarray()) {
+      None => return Err(Error::Format("cifar pickle: missing 'data' array".to_owned())),
+      Some(array) => array,
+    };
+    if dtype != "u1" {
+      return Err(Error::Format(format!("cifar pickle: unexpected 'data' dtype: {}", dtype)));
+    }
+    if shape.len() != 2 || shape[1] != PICKLE_FRAME_SZ || data.len() != shape[0] * PICKLE_FRAME_SZ {
+      return Err(Error::Format(format!("cifar pickle: unexpected 'data' shape: {:?}", shape)));
+    }
+    let len = shape[0];
+    let labels = pickle_labels(&batch, <Flavor as KrizhevskyCifarFlavor>::pickle_label_key(), len)?;
+    let coarse_labels = match <Flavor as KrizhevskyCifarFlavor>::pickle_coarse_label_key() {
+      None => None,
+      Some(key) => Some(pickle_labels(&batch, key, len)?),
+    };
+    // Release the decoded batch so that the pixel data is moved, not copied.
+    drop(batch);
+    let data = Rc::try_unwrap(data).unwrap_or_else(|data| (*data).clone());
+    Ok(KrizhevskyCifarPickleData{
+      len:      len,
+      frame_d:  (32, 32, 3),
+      labels:   labels,
+      coarse_labels:    coarse_labels,
+      coarse:   false,
+      data_m:   SharedMem::new(data),
+      _marker:  PhantomData,
+    })
+  }
+
+  /// Yields the coarse superclass label instead of the fine label.
+  pub fn coarse_labels(mut self) -> KrizhevskyCifarPickleData<Flavor> {
+    if self.coarse_labels.is_none() {
+      panic!("cifar: this flavor has no coarse labels");
+    }
+    self.coarse = true;
+    self
+  }
+
+  /// Yields both labels as `(fine, coarse)`.
+  pub fn fine_and_coarse_labels(self) -> KrizhevskyCifarPickleFineCoarseData<Flavor> {
+    if self.coarse_labels.is_none() {
+      panic!("cifar: this flavor has no coarse labels");
+    }
+    KrizhevskyCifarPickleFineCoarseData{inner: self}
+  }
+
+  fn _frame(&self, idx: usize) -> Array3d<u8, SharedMem<u8>> {
+    let frame_buf = self.data_m.slice_v2(idx * PICKLE_FRAME_SZ .. (idx+1) * PICKLE_FRAME_SZ);
+    Array3d::from_storage(self.frame_d, frame_buf)
+  }
+
+  /// The names of the labels currently yielded, coarse or fine.
+  pub fn label_names(&self) -> &'static [&'static str] {
+    if self.coarse {
+      <Flavor as KrizhevskyCifarFlavor>::coarse_label_names()
+    } else {
+      <Flavor as KrizhevskyCifarFlavor>::label_names()
+    }
+  }
+
+  pub fn coarse_label_names(&self) -> &'static [&'static str] {
+    <Flavor as KrizhevskyCifarFlavor>::coarse_label_names()
+  }
+}
+
+impl<Flavor> IndexedData for KrizhevskyCifarPickleData<Flavor> where Flavor: KrizhevskyCifarFlavor {
+  type Item = (Array3d<u8, SharedMem<u8>>, u32);
+
+  fn len(&self) -> usize {
+    self.len
+  }
+
+  fn get(&mut self, idx: usize) -> Self::Item {
+    let label = match (self.coarse, self.coarse_labels.as_ref()) {
+      (true, Some(coarse_labels)) => coarse_labels[idx],
+      _ => self.labels[idx],
+    };
+    (self._frame(idx), label)
+  }
+}
+
+impl_stateless!(KrizhevskyCifarPickleData<Flavor>);
+
+#[derive(Clone)]
+pub struct KrizhevskyCifarPickleFineCoarseData<Flavor> {
+  inner:    KrizhevskyCifarPickleData<Flavor>,
+}
+
+impl<Flavor> IndexedData for KrizhevskyCifarPickleFineCoarseData<Flavor> where Flavor: KrizhevskyCifarFlavor {
+  type Item = (Array3d<u8, SharedMem<u8>>, (u32, u32));
+
+  fn len(&self) -> usize {
+    self.inner.len
+  }
+
+  fn get(&mut self, idx: usize) -> Self::Item {
+    let fine_label = self.inner.labels[idx];
+    let coarse_label = self.inner.coarse_labels.as_ref().unwrap()[idx];
+    (self.inner._frame(idx), (fine_label, coarse_label))
+  }
+}
+
+impl_stateless!(KrizhevskyCifarPickleFineCoarseData<Flavor>);
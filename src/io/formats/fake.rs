@@ -45,3 +45,5 @@ impl IndexedData for Fake3dData {
     (Array3d::from_storage(self.dim, self.buf.clone()), 0)
   }
 }
+
+impl_stateless!(Fake3dData);
@@ -0,0 +1,218 @@
+/*
+Copyright 2017 the superlearn authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use io::*;
+
+use byteorder::{ByteOrder, ReadBytesExt, BigEndian};
+use densearray::prelude::*;
+use sharedmem::{MemoryMap, SharedMem};
+
+use std::path::{PathBuf};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IdxDtype {
+  U8,
+  I8,
+  I16,
+  I32,
+  F32,
+  F64,
+}
+
+impl IdxDtype {
+  pub fn from_code(code: u8) -> Option<IdxDtype> {
+    match code {
+      0x08 => Some(IdxDtype::U8),
+      0x09 => Some(IdxDtype::I8),
+      0x0b => Some(IdxDtype::I16),
+      0x0c => Some(IdxDtype::I32),
+      0x0d => Some(IdxDtype::F32),
+      0x0e => Some(IdxDtype::F64),
+      _ => None,
+    }
+  }
+
+  pub fn size(&self) -> usize {
+    match *self {
+      IdxDtype::U8 | IdxDtype::I8 => 1,
+      IdxDtype::I16 => 2,
+      IdxDtype::I32 | IdxDtype::F32 => 4,
+      IdxDtype::F64 => 8,
+    }
+  }
+
+  pub fn is_integer(&self) -> bool {
+    match *self {
+      IdxDtype::F32 | IdxDtype::F64 => false,
+      _ => true,
+    }
+  }
+}
+
+/// An mmapped IDX file of any element type and rank. The first dimension
+/// indexes items; the remaining dimensions are the shape of each item.
+/// Elements are stored big-endian and converted on access.
+#[derive(Clone)]
+pub struct IdxArray {
+  dtype:    IdxDtype,
+  dims:     Vec<usize>,
+  item_len: usize,
+  data_m:   SharedMem<u8>,
+}
+
+impl IdxArray {
+  pub fn open(path: PathBuf) -> IdxArray {
+    match Self::try_open(path) {
+      Ok(array) => array,
+      Err(e) => panic!("failed to open idx file: {}", e),
+    }
+  }
+
+  pub fn try_open(path: PathBuf) -> Result<IdxArray, Error> {
+    let mut file = open_file(&path)?;
+    let file_sz = file.metadata()?.len() as usize;
+    let magic: u32 = file.read_u32::<BigEndian>()?;
+    if (magic >> 16) != 0 {
+      return Err(Error::Format(format!("idx: bad magic number: 0x{:08x}", magic)));
+    }
+    let dtype = match IdxDtype::from_code((magic >> 8) as u8) {
+      None => return Err(Error::Format(format!("idx: unknown element type in magic number: 0x{:08x}", magic))),
+      Some(dtype) => dtype,
+    };
+    let ndims = (magic & 0xff) as usize;
+    if ndims == 0 {
+      return Err(Error::Format("idx: zero dimensions".to_owned()));
+    }
+    let mut dims = Vec::with_capacity(ndims);
+    for _ in 0 .. ndims {
+      dims.push(file.read_u32::<BigEndian>()? as usize);
+    }
+    let item_len = dims[1 .. ].iter().fold(1, |n, &d| n * d);
+    let data_sz = dims[0] * item_len * dtype.size();
+    let header_sz = (1 + ndims) * 4;
+    if header_sz + data_sz > file_sz {
+      return Err(Error::Format(format!("idx: truncated file: expected {} bytes, got {}", header_sz + data_sz, file_sz)));
+    }
+    let data_m = if data_sz == 0 {
+      SharedMem::new(Vec::new())
+    } else {
+      match MemoryMap::open_with_offset(file, header_sz, data_sz) {
+        Ok(buf) => SharedMem::new(buf),
+        Err(e) => return Err(Error::Mmap(format!("'{}': {:?}", path.display(), e))),
+      }
+    };
+    Ok(IdxArray{
+      dtype:    dtype,
+      dims:     dims,
+      item_len: item_len,
+      data_m:   data_m,
+    })
+  }
+
+  pub fn dtype(&self) -> IdxDtype {
+    self.dtype
+  }
+
+  /// All dimensions in file (row-major) order, starting with the item count.
+  pub fn dims(&self) -> &[usize] {
+    &self.dims
+  }
+
+  /// The shape of one item in row-major order.
+  pub fn item_dims(&self) -> &[usize] {
+    &self.dims[1 .. ]
+  }
+
+  /// The number of elements in one item.
+  pub fn item_len(&self) -> usize {
+    self.item_len
+  }
+
+  /// The raw big-endian bytes of an item.
+  pub fn get_bytes(&self, idx: usize) -> SharedMem<u8> {
+    assert!(idx < self.dims[0]);
+    let item_sz = self.item_len * self.dtype.size();
+    self.data_m.slice_v2(idx * item_sz .. (idx + 1) * item_sz)
+  }
+
+  fn elem_f64(&self, buf: &[u8]) -> f64 {
+    match self.dtype {
+      IdxDtype::U8  => buf[0] as f64,
+      IdxDtype::I8  => buf[0] as i8 as f64,
+      IdxDtype::I16 => BigEndian::read_i16(buf) as f64,
+      IdxDtype::I32 => BigEndian::read_i32(buf) as f64,
+      IdxDtype::F32 => BigEndian::read_f32(buf) as f64,
+      IdxDtype::F64 => BigEndian::read_f64(buf),
+    }
+  }
+
+  fn elem_i64(&self, buf: &[u8]) -> i64 {
+    match self.dtype {
+      IdxDtype::U8  => buf[0] as i64,
+      IdxDtype::I8  => buf[0] as i8 as i64,
+      IdxDtype::I16 => BigEndian::read_i16(buf) as i64,
+      IdxDtype::I32 => BigEndian::read_i32(buf) as i64,
+      IdxDtype::F32 => BigEndian::read_f32(buf) as i64,
+      IdxDtype::F64 => BigEndian::read_f64(buf) as i64,
+    }
+  }
+
+  pub fn get_f32(&self, idx: usize) -> Vec<f32> {
+    self.get_f64(idx).into_iter().map(|x| x as f32).collect()
+  }
+
+  pub fn get_f64(&self, idx: usize) -> Vec<f64> {
+    let buf = self.get_bytes(idx);
+    let elem_sz = self.dtype.size();
+    let mut xs = Vec::with_capacity(self.item_len);
+    for i in 0 .. self.item_len {
+      xs.push(self.elem_f64(&buf[i * elem_sz .. (i + 1) * elem_sz]));
+    }
+    xs
+  }
+
+  pub fn get_i64(&self, idx: usize) -> Vec<i64> {
+    let buf = self.get_bytes(idx);
+    let elem_sz = self.dtype.size();
+    let mut xs = Vec::with_capacity(self.item_len);
+    for i in 0 .. self.item_len {
+      xs.push(self.elem_i64(&buf[i * elem_sz .. (i + 1) * elem_sz]));
+    }
+    xs
+  }
+
+  /// The value of a scalar item, e.g. a label.
+  pub fn get_scalar_i64(&self, idx: usize) -> i64 {
+    assert_eq!(self.item_len, 1);
+    let buf = self.get_bytes(idx);
+    self.elem_i64(&buf)
+  }
+}
+
+/// Items are flattened and converted to `f32`; see `item_dims` for the shape.
+impl IndexedData for IdxArray {
+  type Item = Array1d<f32, Vec<f32>>;
+
+  fn len(&self) -> usize {
+    self.dims[0]
+  }
+
+  fn get(&mut self, idx: usize) -> Self::Item {
+    Array1d::from_storage(self.item_len, self.get_f32(idx))
+  }
+}
+
+impl_stateless!(IdxArray);
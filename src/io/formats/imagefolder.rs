@@ -0,0 +1,173 @@
+/*
+Copyright 2017 the superlearn authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use io::*;
+
+use sharedmem::{MemoryMap, SharedMem};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{read_dir};
+use std::path::{Path, PathBuf};
+
+const IMAGE_EXTENSIONS: &'static [&'static str] = &["jpg", "jpeg", "png"];
+
+fn sorted_dir_entries(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+  let mut paths = vec![];
+  let entries = match read_dir(dir) {
+    Ok(entries) => entries,
+    Err(e) => return Err(Error::Open(dir.to_owned(), e)),
+  };
+  for entry in entries {
+    let entry = entry?;
+    let hidden = entry.file_name().to_str().map_or(false, |name| name.starts_with('.'));
+    if !hidden {
+      paths.push(entry.path());
+    }
+  }
+  paths.sort();
+  Ok(paths)
+}
+
+fn is_image_path(path: &Path) -> bool {
+  match path.extension().and_then(|ext| ext.to_str()) {
+    None => false,
+    Some(ext) => {
+      let ext = ext.to_lowercase();
+      IMAGE_EXTENSIONS.iter().any(|&e| e == ext)
+    }
+  }
+}
+
+/// A dataset stored as a directory tree of the form `root/<class>/<file>`.
+/// Image files are mmapped on each access.
+#[derive(Clone)]
+pub struct ImageFolderData {
+  class_names:  BTreeMap<u32, String>,
+  paths:        Vec<PathBuf>,
+  labels:       Vec<u32>,
+}
+
+impl ImageFolderData {
+  /// Assigns labels `0 .. n` to the class subdirectories in sorted order.
+  pub fn open(root: PathBuf) -> ImageFolderData {
+    match Self::try_open(root) {
+      Ok(data) => data,
+      Err(e) => panic!("failed to open image folder data: {}", e),
+    }
+  }
+
+  pub fn try_open(root: PathBuf) -> Result<ImageFolderData, Error> {
+    let mut class_map = HashMap::new();
+    for class_dir in sorted_dir_entries(&root)?.into_iter() {
+      if !class_dir.is_dir() {
+        continue;
+      }
+      let class_name = match class_dir.file_name().and_then(|name| name.to_str()) {
+        None => return Err(Error::Format(format!("non-utf8 class directory: {:?}", class_dir))),
+        Some(name) => name.to_owned(),
+      };
+      let label = class_map.len() as u32;
+      class_map.insert(class_name, label);
+    }
+    Self::try_open_with_classes(root, class_map)
+  }
+
+  /// Assigns labels from a class name to label map; subdirectories that are
+  /// not in the map are skipped.
+  pub fn open_with_classes(root: PathBuf, class_map: HashMap<String, u32>) -> ImageFolderData {
+    match Self::try_open_with_classes(root, class_map) {
+      Ok(data) => data,
+      Err(e) => panic!("failed to open image folder data: {}", e),
+    }
+  }
+
+  pub fn try_open_with_classes(root: PathBuf, class_map: HashMap<String, u32>) -> Result<ImageFolderData, Error> {
+    let mut class_names = BTreeMap::new();
+    for (name, &label) in class_map.iter() {
+      if let Some(prev_name) = class_names.insert(label, name.clone()) {
+        return Err(Error::Format(format!("image folder: classes '{}' and '{}' share label {}", prev_name, name, label)));
+      }
+    }
+    let mut paths = vec![];
+    let mut labels = vec![];
+    for class_dir in sorted_dir_entries(&root)?.into_iter() {
+      if !class_dir.is_dir() {
+        continue;
+      }
+      let label = match class_dir.file_name().and_then(|name| name.to_str()).and_then(|name| class_map.get(name)) {
+        None => continue,
+        Some(&label) => label,
+      };
+      for path in sorted_dir_entries(&class_dir)?.into_iter() {
+        if path.is_file() && is_image_path(&path) {
+          paths.push(path);
+          labels.push(label);
+        }
+      }
+    }
+    Ok(ImageFolderData{
+      class_names:  class_names,
+      paths:        paths,
+      labels:       labels,
+    })
+  }
+
+  pub fn num_categories(&self) -> usize {
+    self.class_names.len()
+  }
+
+  pub fn class_name(&self, label: u32) -> Option<&str> {
+    self.class_names.get(&label).map(|name| name.as_str())
+  }
+
+  pub fn path(&self, idx: usize) -> &Path {
+    &self.paths[idx]
+  }
+}
+
+impl IndexedData for ImageFolderData {
+  type Item = (SharedMem<u8>, u32);
+
+  fn len(&self) -> usize {
+    self.paths.len()
+  }
+
+  fn get(&mut self, idx: usize) -> Self::Item {
+    match self.try_get(idx) {
+      Ok(item) => item,
+      Err(e) => panic!("failed to read image folder item: {}", e),
+    }
+  }
+
+  fn try_get(&mut self, idx: usize) -> Result<Self::Item, Error> {
+    let path = match self.paths.get(idx) {
+      None => return Err(Error::OutOfRange(idx, self.paths.len())),
+      Some(path) => path,
+    };
+    let file = open_file(path)?;
+    let file_sz = file.metadata()?.len() as usize;
+    if file_sz == 0 {
+      return Err(Error::Corrupt(idx, format!("empty file: '{}'", path.display())));
+    }
+    let buf = match MemoryMap::open_with_offset(file, 0, file_sz) {
+      Ok(buf) => SharedMem::new(buf),
+      Err(e) => return Err(Error::Mmap(format!("'{}': {:?}", path.display(), e))),
+    };
+    Ok((buf, self.labels[idx]))
+  }
+}
+
+impl_stateless!(ImageFolderData);
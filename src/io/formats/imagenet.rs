@@ -15,6 +15,7 @@ limitations under the License.
 */
 
 use io::*;
+use io::formats::matlab::{MatValue, load_mat_file};
 
 use csv::{Reader as CsvReader};
 use extar::{TarBufferExt, TarBuffer};
@@ -22,11 +23,14 @@ use sharedmem::{MemoryMap, SharedMem};
 use tar::{Archive};
 
 //use byteorder::{ReadBytesExt, BigEndian};
-use std::collections::{HashMap};
-use std::fs::{File};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Debug};
 //use std::io::{Read, Seek, BufRead, BufReader, Cursor, SeekFrom, Result as IoResult};
-use std::io::{BufRead, BufReader, Cursor};
-use std::path::{PathBuf};
+use std::fs::{File, rename};
+use std::io::{BufRead, BufReader, BufWriter, Cursor, Write, stderr};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
 
 /*pub struct BytesCursor<A> {
   inner:    Cursor<A>,
@@ -56,19 +60,124 @@ impl<A> Read for BytesCursor<A> where A: AsRef<[u8]> {
   }
 }*/
 
+/// One row of the `synsets` struct array in the ILSVRC2012 devkit `meta.mat`.
+#[derive(Clone, Debug)]
+pub struct Ilsvrc2012Synset {
+  pub ilsvrc2012_id:    i64,
+  pub wnid:             String,
+  pub words:            String,
+  pub gloss:            String,
+  pub num_children:     usize,
+  pub children:         Vec<i64>,
+}
+
+pub fn load_ilsvrc2012_synsets(path: PathBuf) -> Result<Vec<Ilsvrc2012Synset>, Error> {
+  let vars = load_mat_file(path)?;
+  let synsets = match vars.get("synsets") {
+    Some(synsets @ &MatValue::Struct{..}) => synsets,
+    _ => return Err(Error::Format("meta.mat: missing 'synsets' struct array".to_owned())),
+  };
+  fn field<'a>(synsets: &'a MatValue, idx: usize, name: &str) -> Result<&'a MatValue, Error> {
+    match synsets.field(idx, name) {
+      None => Err(Error::Format(format!("meta.mat: synset {}: missing field '{}'", idx, name))),
+      Some(value) => Ok(value),
+    }
+  }
+  fn str_field(synsets: &MatValue, idx: usize, name: &str) -> Result<String, Error> {
+    match field(synsets, idx, name)?.as_str() {
+      None => Err(Error::Format(format!("meta.mat: synset {}: field '{}' is not a string", idx, name))),
+      Some(s) => Ok(s.to_owned()),
+    }
+  }
+  fn num_field(synsets: &MatValue, idx: usize, name: &str) -> Result<f64, Error> {
+    match field(synsets, idx, name)?.as_f64() {
+      None => Err(Error::Format(format!("meta.mat: synset {}: field '{}' is not a number", idx, name))),
+      Some(x) => Ok(x),
+    }
+  }
+  let mut rows = Vec::with_capacity(synsets.num_elems());
+  for idx in 0 .. synsets.num_elems() {
+    let children = match field(synsets, idx, "children")?.as_f64_slice() {
+      None => return Err(Error::Format(format!("meta.mat: synset {}: field 'children' is not numeric", idx))),
+      Some(children) => children.iter().map(|&c| c as i64).collect(),
+    };
+    rows.push(Ilsvrc2012Synset{
+      ilsvrc2012_id:    num_field(synsets, idx, "ILSVRC2012_ID")? as i64,
+      wnid:             str_field(synsets, idx, "WNID")?,
+      words:            str_field(synsets, idx, "words")?,
+      gloss:            str_field(synsets, idx, "gloss")?,
+      num_children:     num_field(synsets, idx, "num_children")? as usize,
+      children:         children,
+    });
+  }
+  Ok(rows)
+}
+
 #[derive(Clone)]
 pub struct WordnetIlsvrc2012IdMap {
   wnid_to_id:   HashMap<String, i64>,
   id_to_wnid:   HashMap<i64, String>,
+  words:        HashMap<String, String>,
+  parents:      HashMap<String, Vec<String>>,
+  children:     HashMap<String, Vec<String>>,
 }
 
 impl WordnetIlsvrc2012IdMap {
+  fn new(wnid_to_id: HashMap<String, i64>, id_to_wnid: HashMap<i64, String>) -> Self {
+    WordnetIlsvrc2012IdMap{
+      wnid_to_id:   wnid_to_id,
+      id_to_wnid:   id_to_wnid,
+      words:        HashMap::new(),
+      parents:      HashMap::new(),
+      children:     HashMap::new(),
+    }
+  }
+
   pub fn from_metadata(path: PathBuf) -> Self {
-    panic!("unimplemented: loading 'mat' files is unsupported");
+    match Self::try_from_metadata(path) {
+      Ok(map) => map,
+      Err(e) => panic!("failed to load wnid id map: {}", e),
+    }
+  }
+
+  /// Builds the map directly from the devkit `meta.mat`. As with the CSV,
+  /// only the 1000 leaf synsets (ids 1 to 1000) are mapped to ids; the
+  /// descriptions and parent/child hierarchy cover all synsets.
+  pub fn try_from_metadata(path: PathBuf) -> Result<Self, Error> {
+    let synsets = load_ilsvrc2012_synsets(path)?;
+    let mut all_id_to_wnid = HashMap::new();
+    let mut wnid_to_id = HashMap::new();
+    let mut id_to_wnid = HashMap::new();
+    for synset in synsets.iter() {
+      all_id_to_wnid.insert(synset.ilsvrc2012_id, synset.wnid.clone());
+      if synset.ilsvrc2012_id >= 1 && synset.ilsvrc2012_id <= 1000 {
+        wnid_to_id.insert(synset.wnid.clone(), synset.ilsvrc2012_id);
+        id_to_wnid.insert(synset.ilsvrc2012_id, synset.wnid.clone());
+      }
+    }
+    let mut map = Self::new(wnid_to_id, id_to_wnid);
+    for synset in synsets.into_iter() {
+      for &child_id in synset.children.iter() {
+        let child_wnid = match all_id_to_wnid.get(&child_id) {
+          None => return Err(Error::Format(format!("meta.mat: synset '{}' has unknown child id: {}", synset.wnid, child_id))),
+          Some(child_wnid) => child_wnid.clone(),
+        };
+        map.add_edge(synset.wnid.clone(), child_wnid);
+      }
+      map.words.insert(synset.wnid, synset.words);
+    }
+    Ok(map)
   }
 
   pub fn from_csv(path: PathBuf) -> Self {
-    let file = File::open(&path).unwrap();
+    match Self::try_from_csv(path) {
+      Ok(map) => map,
+      Err(e) => panic!("failed to load wnid id map: {}", e),
+    }
+  }
+
+  pub fn try_from_csv(path: PathBuf) -> Result<Self, Error> {
+    let file = open_file(&path)?;
     let mut reader = CsvReader::from_reader(file).has_headers(true);
     let mut wnid_to_id = HashMap::new();
     let mut id_to_wnid = HashMap::new();
@@ -77,15 +186,111 @@ impl WordnetIlsvrc2012IdMap {
       wordnet_id:       String,
       ilsvrc2012_id:    i64,
     }
-    for row in reader.decode() {
-      let row: Row = row.unwrap();
+    for (row_idx, row) in reader.decode().enumerate() {
+      let row: Row = match row {
+        Ok(row) => row,
+        Err(e) => return Err(Error::Format(format!("wnid id map: row {}: {:?}", row_idx, e))),
+      };
       wnid_to_id.insert(row.wordnet_id.clone(), row.ilsvrc2012_id);
       id_to_wnid.insert(row.ilsvrc2012_id, row.wordnet_id);
     }
-    WordnetIlsvrc2012IdMap{
-      wnid_to_id:   wnid_to_id,
-      id_to_wnid:   id_to_wnid,
+    Ok(Self::new(wnid_to_id, id_to_wnid))
+  }
+
+  /// Loads class descriptions from a WordNet `words.txt` (one
+  /// `<wnid>\t<words>` pair per line), replacing any existing ones.
+  pub fn load_words(&mut self, path: PathBuf) {
+    if let Err(e) = self.try_load_words(path) {
+      panic!("failed to load wordnet words: {}", e);
+    }
+  }
+
+  pub fn try_load_words(&mut self, path: PathBuf) -> Result<(), Error> {
+    let file = open_file(&path)?;
+    let reader = BufReader::new(file);
+    for (line_nr, line) in reader.lines().enumerate() {
+      let line = line?;
+      let mut toks = line.splitn(2, '\t');
+      match (toks.next(), toks.next()) {
+        (Some(wnid), Some(words)) => {
+          self.words.insert(wnid.trim().to_owned(), words.trim().to_owned());
+        }
+        _ => {
+          if line.trim().is_empty() {
+            continue;
+          }
+          return Err(Error::Format(format!("wordnet words: line {}: expected '<wnid>\\t<words>'", line_nr + 1)));
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Loads the WordNet hierarchy from a `wordnet.is_a.txt` (one
+  /// `<parent wnid> <child wnid>` pair per line).
+  pub fn load_hierarchy(&mut self, path: PathBuf) {
+    if let Err(e) = self.try_load_hierarchy(path) {
+      panic!("failed to load wordnet hierarchy: {}", e);
+    }
+  }
+
+  pub fn try_load_hierarchy(&mut self, path: PathBuf) -> Result<(), Error> {
+    let file = open_file(&path)?;
+    let reader = BufReader::new(file);
+    for (line_nr, line) in reader.lines().enumerate() {
+      let line = line?;
+      let mut toks = line.split_whitespace();
+      match (toks.next(), toks.next(), toks.next()) {
+        (None, _, _) => continue,
+        (Some(parent), Some(child), None) => {
+          self.add_edge(parent.to_owned(), child.to_owned());
+        }
+        _ => return Err(Error::Format(format!("wordnet hierarchy: line {}: expected '<parent> <child>'", line_nr + 1))),
+      }
+    }
+    Ok(())
+  }
+
+  fn add_edge(&mut self, parent: String, child: String) {
+    {
+      let parents = self.parents.entry(child.clone()).or_insert_with(Vec::new);
+      if parents.contains(&parent) {
+        return;
+      }
+      parents.push(parent.clone());
+    }
+    self.children.entry(parent).or_insert_with(Vec::new).push(child);
+  }
+
+  fn wnid_to_label(&self, wnid: &str) -> Result<u32, Error> {
+    let id = match self.wnid_to_id.get(wnid) {
+      None => return Err(Error::UnknownWnid(wnid.to_owned())),
+      Some(&id) => id,
+    };
+    if id < 1 || id > 1000 {
+      return Err(Error::Format(format!("wnid '{}' has out of range ilsvrc2012 id: {}", wnid, id)));
     }
+    Ok((id - 1) as u32)
+  }
+
+  /// A stable FNV-1a hash of the wnid to label assignment of the leaf
+  /// classes, used to detect indexes built with a different map.
+  fn label_fingerprint(&self) -> u64 {
+    let mut pairs: Vec<(&str, i64)> = self.wnid_to_id.iter()
+      .filter(|&(_, &id)| id >= 1 && id <= 1000)
+      .map(|(wnid, &id)| (wnid.as_str(), id))
+      .collect();
+    pairs.sort();
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for &(wnid, id) in pairs.iter() {
+      let mut id_buf = [0; 8];
+      LittleEndian::write_i64(&mut id_buf, id);
+      for &b in wnid.as_bytes().iter().chain(&[0]).chain(id_buf.iter()) {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+      }
+    }
+    h
   }
 
   pub fn len(&self) -> usize {
@@ -94,6 +299,89 @@ impl WordnetIlsvrc2012IdMap {
     assert_eq!(n1, n2);
     n1
   }
+
+  /// The wnid of a 0-based class label.
+  pub fn wnid(&self, label: u32) -> Option<&str> {
+    self.id_to_wnid.get(&(label as i64 + 1)).map(|wnid| wnid.as_str())
+  }
+
+  /// The 0-based class label of a wnid, if it is one of the 1000 leaf classes.
+  pub fn label(&self, wnid: &str) -> Option<u32> {
+    self.wnid_to_label(wnid).ok()
+  }
+
+  /// The full WordNet description of a wnid, e.g. "tench, Tinca tinca".
+  pub fn words(&self, wnid: &str) -> Option<&str> {
+    self.words.get(wnid).map(|words| words.as_str())
+  }
+
+  /// A short human-readable name for a class label: the first synonym of its
+  /// description, or the wnid if no description is loaded.
+  pub fn class_name(&self, label: u32) -> Option<&str> {
+    let wnid = match self.wnid(label) {
+      None => return None,
+      Some(wnid) => wnid,
+    };
+    match self.words(wnid) {
+      None => Some(wnid),
+      Some(words) => Some(words.split(',').next().unwrap_or(words).trim()),
+    }
+  }
+
+  pub fn parents(&self, wnid: &str) -> &[String] {
+    match self.parents.get(wnid) {
+      None => &[],
+      Some(parents) => parents,
+    }
+  }
+
+  pub fn children(&self, wnid: &str) -> &[String] {
+    match self.children.get(wnid) {
+      None => &[],
+      Some(children) => children,
+    }
+  }
+
+  /// All transitive ancestors of a wnid in breadth-first order, excluding the
+  /// wnid itself. WordNet is a DAG, so each ancestor appears only once.
+  pub fn ancestors(&self, wnid: &str) -> Vec<String> {
+    let mut ancestors: Vec<String> = vec![];
+    let mut queue: VecDeque<&str> = VecDeque::new();
+    queue.push_back(wnid);
+    while let Some(w) = queue.pop_front() {
+      for parent in self.parents(w).iter() {
+        if parent == wnid || ancestors.contains(parent) {
+          continue;
+        }
+        ancestors.push(parent.clone());
+        queue.push_back(parent);
+      }
+    }
+    ancestors
+  }
+
+  pub fn is_ancestor(&self, ancestor: &str, wnid: &str) -> bool {
+    self.ancestors(wnid).iter().any(|w| w == ancestor)
+  }
+
+  /// Maps every fine label to the index of the first of `coarse_wnids` that
+  /// is (or is an ancestor of) its synset, for computing coarse-grained
+  /// accuracy. Labels not covered by any coarse synset map to `None`.
+  pub fn coarse_labels(&self, coarse_wnids: &[&str]) -> Vec<Option<u32>> {
+    let mut coarse_labels = Vec::with_capacity(1000);
+    for label in 0 .. 1000 {
+      let coarse = match self.wnid(label) {
+        None => None,
+        Some(wnid) => {
+          let ancestors = self.ancestors(wnid);
+          coarse_wnids.iter().position(|&c| c == wnid || ancestors.iter().any(|a| a == c))
+            .map(|idx| idx as u32)
+        }
+      };
+      coarse_labels.push(coarse);
+    }
+    coarse_labels
+  }
 }
 
 #[derive(Clone)]
@@ -103,15 +391,25 @@ pub struct Ilsvrc2012ValidGroundTruth {
 
 impl Ilsvrc2012ValidGroundTruth {
   pub fn open(path: PathBuf) -> Self {
-    let file = File::open(&path).unwrap();
+    match Self::try_open(path) {
+      Ok(truth) => truth,
+      Err(e) => panic!("failed to load ilsvrc2012 valid ground truth: {}", e),
+    }
+  }
+
+  pub fn try_open(path: PathBuf) -> Result<Self, Error> {
+    let file = open_file(&path)?;
     let reader = BufReader::new(file);
     let mut ids = vec![];
-    for line in reader.lines() {
-      let line = line.unwrap();
-      let id: i64 = line.parse().unwrap();
+    for (line_nr, line) in reader.lines().enumerate() {
+      let line = line?;
+      let id: i64 = match line.trim().parse() {
+        Ok(id) => id,
+        Err(e) => return Err(Error::Format(format!("valid ground truth: line {}: {:?}", line_nr + 1, e))),
+      };
       ids.push(id);
     }
-    Ilsvrc2012ValidGroundTruth{ids: ids}
+    Ok(Ilsvrc2012ValidGroundTruth{ids: ids})
   }
 }
 
@@ -122,6 +420,134 @@ pub struct Entry {
   label:    Option<u32>,
 }
 
+fn mmap_archive(archive_path: &Path) -> Result<SharedMem<u8>, Error> {
+  let archive_file = open_file(archive_path)?;
+  let file_meta = archive_file.metadata()?;
+  let file_sz = file_meta.len() as usize;
+  match MemoryMap::open_with_offset(archive_file, 0, file_sz) {
+    Ok(buf) => Ok(SharedMem::new(buf)),
+    Err(e) => Err(Error::Mmap(format!("'{}': {:?}", archive_path.display(), e))),
+  }
+}
+
+fn tar_error<E>(e: E) -> Error where E: Debug {
+  Error::Format(format!("tar: {:?}", e))
+}
+
+fn entry_path_str(path: &Path) -> Result<&str, Error> {
+  match path.to_str() {
+    Some(s) => Ok(s),
+    None => Err(Error::Format(format!("non-utf8 archive member path: {:?}", path))),
+  }
+}
+
+const INDEX_MAGIC: &'static [u8] = b"SLARIDX2";
+const INDEX_HEADER_SZ: usize = 48;
+const INDEX_ENTRY_SZ: usize = 24;
+const INDEX_NO_LABEL: u32 = 0xffff_ffff;
+
+/// Archive size, mtime (seconds, nanoseconds) and the label fingerprint of
+/// the wnid id map, used to invalidate indexes.
+type IndexStamp = (u64, u64, u32, u64);
+
+fn index_sidecar_path(archive_path: &Path) -> PathBuf {
+  let mut name = archive_path.as_os_str().to_owned();
+  name.push(".index");
+  PathBuf::from(name)
+}
+
+fn index_stamp(archive_path: &Path, wnid_id_map: &WordnetIlsvrc2012IdMap) -> Result<IndexStamp, Error> {
+  let file_meta = open_file(archive_path)?.metadata()?;
+  let mtime = match file_meta.modified()?.duration_since(UNIX_EPOCH) {
+    Ok(d) => d,
+    Err(_) => Duration::new(0, 0),
+  };
+  Ok((file_meta.len(), mtime.as_secs(), mtime.subsec_nanos(), wnid_id_map.label_fingerprint()))
+}
+
+/// Index layout (little endian): an 8 byte magic, the archive size (u64),
+/// mtime seconds (u64), mtime nanoseconds (u32), 4 reserved bytes, the map
+/// fingerprint (u64) and the number of entries (u64), followed by
+/// `(offset: u64, length: u64, label: u32, reserved: u32)` per entry.
+fn read_entry_index(index_path: &Path, stamp: IndexStamp) -> Result<Option<Vec<Entry>>, Error> {
+  let index_file = match File::open(index_path) {
+    Ok(file) => file,
+    Err(_) => return Ok(None),
+  };
+  let file_sz = index_file.metadata()?.len() as usize;
+  if file_sz < INDEX_HEADER_SZ {
+    return Ok(None);
+  }
+  let index_buf = match MemoryMap::open_with_offset(index_file, 0, file_sz) {
+    Ok(buf) => SharedMem::new(buf),
+    Err(e) => return Err(Error::Mmap(format!("'{}': {:?}", index_path.display(), e))),
+  };
+  let buf: &[u8] = &*index_buf;
+  if &buf[ .. 8] != INDEX_MAGIC {
+    return Ok(None);
+  }
+  let index_stamp = (
+      LittleEndian::read_u64(&buf[8 .. 16]),
+      LittleEndian::read_u64(&buf[16 .. 24]),
+      LittleEndian::read_u32(&buf[24 .. 28]),
+      LittleEndian::read_u64(&buf[32 .. 40]),
+  );
+  if index_stamp != stamp {
+    return Ok(None);
+  }
+  let num_entries = LittleEndian::read_u64(&buf[40 .. 48]) as usize;
+  // A corrupt entry count must not overflow into a bogus size match.
+  match num_entries.checked_mul(INDEX_ENTRY_SZ).and_then(|sz| sz.checked_add(INDEX_HEADER_SZ)) {
+    Some(sz) if sz == file_sz => {}
+    _ => return Ok(None),
+  }
+  let mut entries = Vec::with_capacity(num_entries);
+  for idx in 0 .. num_entries {
+    let p = INDEX_HEADER_SZ + idx * INDEX_ENTRY_SZ;
+    let offset = LittleEndian::read_u64(&buf[p .. p + 8]) as usize;
+    let length = LittleEndian::read_u64(&buf[p + 8 .. p + 16]) as usize;
+    let label = LittleEndian::read_u32(&buf[p + 16 .. p + 20]);
+    if offset + length > stamp.0 as usize {
+      return Err(Error::Corrupt(idx, format!("index entry overruns the archive: '{}'", index_path.display())));
+    }
+    entries.push(Entry{
+      offset:   offset,
+      length:   length,
+      label:    if label == INDEX_NO_LABEL { None } else { Some(label) },
+    });
+  }
+  Ok(Some(entries))
+}
+
+fn write_entry_index(index_path: &Path, stamp: IndexStamp, entries: &[Entry]) -> Result<(), Error> {
+  let mut tmp_name = index_path.as_os_str().to_owned();
+  tmp_name.push(".tmp");
+  let tmp_path = PathBuf::from(tmp_name);
+  {
+    let tmp_file = match File::create(&tmp_path) {
+      Ok(file) => file,
+      Err(e) => return Err(Error::Open(tmp_path.clone(), e)),
+    };
+    let mut writer = BufWriter::new(tmp_file);
+    writer.write_all(INDEX_MAGIC)?;
+    writer.write_u64::<LittleEndian>(stamp.0)?;
+    writer.write_u64::<LittleEndian>(stamp.1)?;
+    writer.write_u32::<LittleEndian>(stamp.2)?;
+    writer.write_u32::<LittleEndian>(0)?;
+    writer.write_u64::<LittleEndian>(stamp.3)?;
+    writer.write_u64::<LittleEndian>(entries.len() as u64)?;
+    for entry in entries.iter() {
+      writer.write_u64::<LittleEndian>(entry.offset as u64)?;
+      writer.write_u64::<LittleEndian>(entry.length as u64)?;
+      writer.write_u32::<LittleEndian>(entry.label.unwrap_or(INDEX_NO_LABEL))?;
+      writer.write_u32::<LittleEndian>(0)?;
+    }
+    writer.flush()?;
+  }
+  rename(&tmp_path, index_path)?;
+  Ok(())
+}
+
 #[derive(Clone)]
 pub struct Ilsvrc2012TrainData {
   wnid_id_map:  WordnetIlsvrc2012IdMap,
@@ -131,11 +557,57 @@ pub struct Ilsvrc2012TrainData {
 
 impl Ilsvrc2012TrainData {
   pub fn open(wnid_id_map: WordnetIlsvrc2012IdMap, archive_path: PathBuf) -> Ilsvrc2012TrainData {
-    let archive_file = File::open(&archive_path).unwrap();
-    let file_meta = archive_file.metadata().unwrap();
-    let file_sz = file_meta.len() as usize;
-    let archive_buf = SharedMem::new(MemoryMap::open_with_offset(archive_file, 0, file_sz).unwrap());
+    match Self::try_open(wnid_id_map, archive_path) {
+      Ok(data) => data,
+      Err(e) => panic!("failed to open ilsvrc2012 train data: {}", e),
+    }
+  }
+
+  pub fn try_open(wnid_id_map: WordnetIlsvrc2012IdMap, archive_path: PathBuf) -> Result<Ilsvrc2012TrainData, Error> {
+    let archive_buf = mmap_archive(&archive_path)?;
+    let entries = Self::scan_entries(&wnid_id_map, &archive_buf)?;
+    Ok(Ilsvrc2012TrainData{
+      wnid_id_map:  wnid_id_map,
+      entries:  entries,
+      data_buf: archive_buf,
+    })
+  }
+
+  /// Like `open`, but reuses a binary index of the archive members stored
+  /// next to the archive (`<archive>.index`). The index is (re)built when it
+  /// is missing or when the archive size or mtime, or the labels assigned by
+  /// `wnid_id_map`, no longer match.
+  pub fn open_indexed(wnid_id_map: WordnetIlsvrc2012IdMap, archive_path: PathBuf) -> Ilsvrc2012TrainData {
+    match Self::try_open_indexed(wnid_id_map, archive_path) {
+      Ok(data) => data,
+      Err(e) => panic!("failed to open ilsvrc2012 train data: {}", e),
+    }
+  }
+
+  pub fn try_open_indexed(wnid_id_map: WordnetIlsvrc2012IdMap, archive_path: PathBuf) -> Result<Ilsvrc2012TrainData, Error> {
+    let archive_buf = mmap_archive(&archive_path)?;
+    let stamp = index_stamp(&archive_path, &wnid_id_map)?;
+    let index_path = index_sidecar_path(&archive_path);
+    let entries = match read_entry_index(&index_path, stamp)? {
+      Some(entries) => entries,
+      None => {
+        let entries = Self::scan_entries(&wnid_id_map, &archive_buf)?;
+        // A read-only dataset directory should not prevent opening the data.
+        if let Err(e) = write_entry_index(&index_path, stamp, &entries) {
+          let _ = writeln!(&mut stderr(), "WARNING: ilsvrc2012 train: failed to write index '{}': {}",
+              index_path.display(), e);
+        }
+        entries
+      }
+    };
+    Ok(Ilsvrc2012TrainData{
+      wnid_id_map:  wnid_id_map,
+      entries:  entries,
+      data_buf: archive_buf,
+    })
+  }
 
+  fn scan_entries(wnid_id_map: &WordnetIlsvrc2012IdMap, archive_buf: &SharedMem<u8>) -> Result<Vec<Entry>, Error> {
     let mut entries = Vec::new();
 
     /*let reader = Cursor::new(archive_buf.clone());
@@ -181,33 +653,33 @@ impl Ilsvrc2012TrainData {
 
     let reader = Cursor::new(archive_buf.clone());
     let mut archive = TarBuffer::new(reader);
-    for (wnid_idx, wnid_entry) in archive.raw_entries().unwrap().enumerate() {
-      let wnid_entry = wnid_entry.unwrap();
+    for (wnid_idx, wnid_entry) in archive.raw_entries().map_err(tar_error)?.enumerate() {
+      let wnid_entry = wnid_entry.map_err(tar_error)?;
       let wnid_pos = wnid_entry.raw_file_position();
       let wnid_size = wnid_entry.file_size();
       //assert_eq!(wnid_size, wnid_entry.header().size().unwrap());
+      if wnid_pos + wnid_size > archive_buf.len() as u64 {
+        return Err(Error::Format(format!("ilsvrc2012 train: wnid archive {} overruns the outer archive", wnid_idx)));
+      }
 
       //let mut wnid_archive = Archive::new(wnid_entry);
       let mut wnid_archive = TarBuffer::new(Cursor::new(archive_buf.slice_v2(wnid_pos as usize .. (wnid_pos + wnid_size) as usize)));
-      for (im_idx, im_entry) in wnid_archive.raw_entries().unwrap().enumerate() {
-        let im_entry = im_entry.unwrap();
+      for (im_idx, im_entry) in wnid_archive.raw_entries().map_err(tar_error)?.enumerate() {
+        let im_entry = im_entry.map_err(tar_error)?;
         let im_pos = im_entry.raw_file_position();
         let im_size = im_entry.file_size();
         //assert_eq!(im_size, im_entry.header().size().unwrap());
-        assert!(wnid_pos + im_pos >= wnid_pos);
-        assert!(wnid_pos + im_pos < wnid_pos + wnid_size);
-        assert!(wnid_pos + im_pos + im_size <= wnid_pos + wnid_size);
+        if im_pos + im_size > wnid_size {
+          return Err(Error::Format(format!("ilsvrc2012 train: image {} overruns wnid archive {}", im_idx, wnid_idx)));
+        }
 
         //let im_path = im_entry.header().path().unwrap().into_owned();
         let im_path = im_entry.path.clone();
-        let im_path_toks: Vec<_> = im_path.to_str().unwrap().splitn(2, ".").collect();
+        let im_path_toks: Vec<_> = entry_path_str(&im_path)?.splitn(2, ".").collect();
         let im_stem_toks: Vec<_> = im_path_toks[0].splitn(2, "_").collect();
         let im_wnid = im_stem_toks[0].to_owned();
 
-        let im_id = *wnid_id_map.wnid_to_id.get(&im_wnid).unwrap();
-        assert!(im_id >= 1);
-        let im_label = (im_id - 1) as u32;
-        assert!(im_label < 1000);
+        let im_label = wnid_id_map.wnid_to_label(&im_wnid)?;
 
         let entry = Entry{
           offset:   (wnid_pos + im_pos) as usize,
@@ -222,11 +694,7 @@ impl Ilsvrc2012TrainData {
       }
     }
 
-    Ilsvrc2012TrainData{
-      wnid_id_map:  wnid_id_map,
-      entries:  entries,
-      data_buf: archive_buf,
-    }
+    Ok(entries)
   }
 
   pub fn num_categories(&self) -> usize {
@@ -246,8 +714,26 @@ impl IndexedData for Ilsvrc2012TrainData {
     let frame_buf = self.data_buf.slice_v2(entry.offset .. entry.offset + entry.length);
     (frame_buf, entry.label.unwrap())
   }
+
+  fn try_get(&mut self, idx: usize) -> Result<Self::Item, Error> {
+    let entry = match self.entries.get(idx) {
+      None => return Err(Error::OutOfRange(idx, self.entries.len())),
+      Some(entry) => *entry,
+    };
+    let label = match entry.label {
+      None => return Err(Error::Corrupt(idx, "missing label".to_owned())),
+      Some(label) => label,
+    };
+    if entry.offset + entry.length > self.data_buf.len() {
+      return Err(Error::Corrupt(idx, "entry overruns the archive".to_owned()));
+    }
+    let frame_buf = self.data_buf.slice_v2(entry.offset .. entry.offset + entry.length);
+    Ok((frame_buf, label))
+  }
 }
 
+impl_stateless!(Ilsvrc2012TrainData);
+
 #[derive(Clone)]
 pub struct Ilsvrc2012ValidData {
   truth:    Ilsvrc2012ValidGroundTruth,
@@ -257,10 +743,14 @@ pub struct Ilsvrc2012ValidData {
 
 impl Ilsvrc2012ValidData {
   pub fn open(truth: Ilsvrc2012ValidGroundTruth, archive_path: PathBuf) -> Self {
-    let archive_file = File::open(&archive_path).unwrap();
-    let file_meta = archive_file.metadata().unwrap();
-    let file_sz = file_meta.len() as usize;
-    let archive_buf = SharedMem::new(MemoryMap::open_with_offset(archive_file, 0, file_sz).unwrap());
+    match Self::try_open(truth, archive_path) {
+      Ok(data) => data,
+      Err(e) => panic!("failed to open ilsvrc2012 valid data: {}", e),
+    }
+  }
+
+  pub fn try_open(truth: Ilsvrc2012ValidGroundTruth, archive_path: PathBuf) -> Result<Self, Error> {
+    let archive_buf = mmap_archive(&archive_path)?;
 
     let mut entries = Vec::new();
 
@@ -296,25 +786,33 @@ impl Ilsvrc2012ValidData {
 
     let reader = Cursor::new(archive_buf.clone());
     let mut archive = TarBuffer::new(reader);
-    for (_, im_entry) in archive.raw_entries().unwrap().enumerate() {
-      let im_entry = im_entry.unwrap();
+    for (_, im_entry) in archive.raw_entries().map_err(tar_error)?.enumerate() {
+      let im_entry = im_entry.map_err(tar_error)?;
       let im_pos = im_entry.raw_file_position();
       let im_size = im_entry.file_size();
       //assert_eq!(im_size, im_entry.header().size().unwrap());
 
       let im_path = im_entry.path.clone();
-      let im_path_toks: Vec<_> = im_path.to_str().unwrap().splitn(2, ".").collect();
+      let im_path_toks: Vec<_> = entry_path_str(&im_path)?.splitn(2, ".").collect();
       let im_stem_toks: Vec<_> = im_path_toks[0].splitn(3, "_").collect();
-      assert_eq!("val", im_stem_toks[1]);
+      if im_stem_toks.len() != 3 || im_stem_toks[1] != "val" {
+        return Err(Error::Format(format!("ilsvrc2012 valid: unexpected member: {:?}", im_path)));
+      }
       let im_rank_tok = im_stem_toks[2].to_owned();
 
-      let im_rank: i64 = im_rank_tok.parse().unwrap();
-      assert!(im_rank >= 1);
+      let im_rank: i64 = match im_rank_tok.parse() {
+        Ok(rank) => rank,
+        Err(_) => return Err(Error::Format(format!("ilsvrc2012 valid: bad rank: {:?}", im_path))),
+      };
+      if im_rank < 1 || im_rank as usize > truth.ids.len() {
+        return Err(Error::Format(format!("ilsvrc2012 valid: rank out of range: {:?}", im_path)));
+      }
       let im_idx = (im_rank - 1) as usize;
       let im_id = truth.ids[im_idx];
-      assert!(im_id >= 1);
+      if im_id < 1 || im_id > 1000 {
+        return Err(Error::Format(format!("ilsvrc2012 valid: id out of range: {}", im_id)));
+      }
       let im_label = (im_id - 1) as u32;
-      assert!(im_label < 1000);
 
       let entry = Entry{
         offset:   im_pos as usize,
@@ -324,13 +822,15 @@ impl Ilsvrc2012ValidData {
       entries.push(entry);
     }
 
-    assert_eq!(truth.ids.len(), entries.len());
+    if truth.ids.len() != entries.len() {
+      return Err(Error::Format(format!("ilsvrc2012 valid: expected {} images, found {}", truth.ids.len(), entries.len())));
+    }
 
-    Ilsvrc2012ValidData{
+    Ok(Ilsvrc2012ValidData{
       truth:    truth,
       entries:  entries,
       data_buf: archive_buf,
-    }
+    })
   }
 }
 
@@ -346,14 +846,105 @@ impl IndexedData for Ilsvrc2012ValidData {
     let frame_buf = self.data_buf.slice_v2(entry.offset .. entry.offset + entry.length);
     (frame_buf, entry.label.unwrap())
   }
+
+  fn try_get(&mut self, idx: usize) -> Result<Self::Item, Error> {
+    let entry = match self.entries.get(idx) {
+      None => return Err(Error::OutOfRange(idx, self.entries.len())),
+      Some(entry) => *entry,
+    };
+    let label = match entry.label {
+      None => return Err(Error::Corrupt(idx, "missing label".to_owned())),
+      Some(label) => label,
+    };
+    if entry.offset + entry.length > self.data_buf.len() {
+      return Err(Error::Corrupt(idx, "entry overruns the archive".to_owned()));
+    }
+    let frame_buf = self.data_buf.slice_v2(entry.offset .. entry.offset + entry.length);
+    Ok((frame_buf, label))
+  }
 }
 
+impl_stateless!(Ilsvrc2012ValidData);
+
 #[derive(Clone)]
 pub struct Ilsvrc2012TestData {
   entries:  Vec<Entry>,
+  names:    Vec<String>,
   data_buf: SharedMem<u8>,
 }
 
+impl Ilsvrc2012TestData {
+  pub fn open(archive_path: PathBuf) -> Self {
+    match Self::try_open(archive_path) {
+      Ok(data) => data,
+      Err(e) => panic!("failed to open ilsvrc2012 test data: {}", e),
+    }
+  }
+
+  /// Indexes `ILSVRC2012_img_test.tar`. Images are ordered by their rank in
+  /// the filename, which is the order expected in submission files.
+  pub fn try_open(archive_path: PathBuf) -> Result<Self, Error> {
+    let archive_buf = mmap_archive(&archive_path)?;
+
+    let mut ranked_entries = Vec::new();
+
+    let reader = Cursor::new(archive_buf.clone());
+    let mut archive = TarBuffer::new(reader);
+    for (_, im_entry) in archive.raw_entries().map_err(tar_error)?.enumerate() {
+      let im_entry = im_entry.map_err(tar_error)?;
+      let im_pos = im_entry.raw_file_position();
+      let im_size = im_entry.file_size();
+
+      let im_path = im_entry.path.clone();
+      let im_name = entry_path_str(&im_path)?.to_owned();
+      let im_path_toks: Vec<_> = im_name.splitn(2, ".").collect();
+      let im_stem_toks: Vec<_> = im_path_toks[0].splitn(3, "_").collect();
+      if im_stem_toks.len() != 3 || im_stem_toks[1] != "test" {
+        return Err(Error::Format(format!("ilsvrc2012 test: unexpected member: {:?}", im_path)));
+      }
+      let im_rank: i64 = match im_stem_toks[2].parse() {
+        Ok(rank) => rank,
+        Err(_) => return Err(Error::Format(format!("ilsvrc2012 test: bad rank: {:?}", im_path))),
+      };
+      if im_rank < 1 {
+        return Err(Error::Format(format!("ilsvrc2012 test: rank out of range: {:?}", im_path)));
+      }
+
+      let entry = Entry{
+        offset:   im_pos as usize,
+        length:   im_size as usize,
+        label:    None,
+      };
+      ranked_entries.push((im_rank, entry, im_name));
+    }
+
+    ranked_entries.sort_by_key(|&(rank, _, _)| rank);
+    for w in ranked_entries.windows(2) {
+      if w[0].0 == w[1].0 {
+        return Err(Error::Format(format!("ilsvrc2012 test: duplicate rank: {}", w[0].0)));
+      }
+    }
+
+    let mut entries = Vec::with_capacity(ranked_entries.len());
+    let mut names = Vec::with_capacity(ranked_entries.len());
+    for (_, entry, name) in ranked_entries {
+      entries.push(entry);
+      names.push(name);
+    }
+
+    Ok(Ilsvrc2012TestData{
+      entries:  entries,
+      names:    names,
+      data_buf: archive_buf,
+    })
+  }
+
+  /// The original archive filename of the image at `idx`.
+  pub fn filename(&self, idx: usize) -> &str {
+    &self.names[idx]
+  }
+}
+
 impl IndexedData for Ilsvrc2012TestData {
   type Item = SharedMem<u8>;
 
@@ -367,3 +958,5 @@ impl IndexedData for Ilsvrc2012TestData {
     frame_buf
   }
 }
+
+impl_stateless!(Ilsvrc2012TestData);
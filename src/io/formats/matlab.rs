@@ -0,0 +1,485 @@
+/*
+Copyright 2017 the superlearn authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! A minimal reader for MATLAB level 5 MAT-files (including the zlib
+//! compressed elements written by MATLAB 7), sufficient for struct, cell,
+//! char and real numeric arrays. Sparse and object arrays are unsupported.
+
+use io::*;
+
+use byteorder::{ByteOrder, BigEndian, LittleEndian};
+use flate2::read::{ZlibDecoder};
+
+use std::collections::{HashMap};
+use std::io::{Read};
+use std::path::{PathBuf};
+
+const MI_INT8:          u32 = 1;
+const MI_UINT8:         u32 = 2;
+const MI_INT16:         u32 = 3;
+const MI_UINT16:        u32 = 4;
+const MI_INT32:         u32 = 5;
+const MI_UINT32:        u32 = 6;
+const MI_SINGLE:        u32 = 7;
+const MI_DOUBLE:        u32 = 9;
+const MI_INT64:         u32 = 12;
+const MI_UINT64:        u32 = 13;
+const MI_MATRIX:        u32 = 14;
+const MI_COMPRESSED:    u32 = 15;
+const MI_UTF8:          u32 = 16;
+const MI_UTF16:         u32 = 17;
+
+const MX_CELL_CLASS:    u32 = 1;
+const MX_STRUCT_CLASS:  u32 = 2;
+const MX_CHAR_CLASS:    u32 = 4;
+const MX_DOUBLE_CLASS:  u32 = 6;
+const MX_UINT64_CLASS:  u32 = 15;
+
+#[derive(Clone, Debug)]
+pub enum MatValue {
+  Empty,
+  Numeric{dims: Vec<usize>, data: Vec<f64>},
+  Char{dims: Vec<usize>, data: String},
+  Cell{dims: Vec<usize>, elems: Vec<MatValue>},
+  /// Field values are stored element-major: `values[elem * fields.len() + field]`.
+  Struct{dims: Vec<usize>, fields: Vec<String>, values: Vec<MatValue>},
+}
+
+impl MatValue {
+  pub fn as_f64(&self) -> Option<f64> {
+    match *self {
+      MatValue::Numeric{ref data, ..} => data.get(0).map(|&x| x),
+      _ => None,
+    }
+  }
+
+  pub fn as_f64_slice(&self) -> Option<&[f64]> {
+    match *self {
+      MatValue::Empty => Some(&[]),
+      MatValue::Numeric{ref data, ..} => Some(data),
+      _ => None,
+    }
+  }
+
+  pub fn as_str(&self) -> Option<&str> {
+    match *self {
+      MatValue::Empty => Some(""),
+      MatValue::Char{ref data, ..} => Some(data),
+      _ => None,
+    }
+  }
+
+  /// The number of elements of a struct or cell array.
+  pub fn num_elems(&self) -> usize {
+    match *self {
+      MatValue::Empty => 0,
+      MatValue::Numeric{ref data, ..} => data.len(),
+      MatValue::Char{ref dims, ..} => dims.iter().fold(1, |n, &d| n * d),
+      MatValue::Cell{ref elems, ..} => elems.len(),
+      MatValue::Struct{ref fields, ref values, ..} => {
+        if fields.is_empty() { 0 } else { values.len() / fields.len() }
+      }
+    }
+  }
+
+  pub fn field(&self, elem: usize, name: &str) -> Option<&MatValue> {
+    match *self {
+      MatValue::Struct{ref fields, ref values, ..} => {
+        let field_idx = match fields.iter().position(|f| f == name) {
+          None => return None,
+          Some(field_idx) => field_idx,
+        };
+        values.get(elem * fields.len() + field_idx)
+      }
+      _ => None,
+    }
+  }
+}
+
+fn pad8(len: usize) -> usize {
+  (len + 7) / 8 * 8
+}
+
+fn format_error(msg: &str) -> Error {
+  Error::Format(format!("mat file: {}", msg))
+}
+
+struct MatReader<'a> {
+  buf:          &'a [u8],
+  pos:          usize,
+  big_endian:   bool,
+}
+
+impl<'a> MatReader<'a> {
+  fn new(buf: &'a [u8], big_endian: bool) -> Self {
+    MatReader{
+      buf:          buf,
+      pos:          0,
+      big_endian:   big_endian,
+    }
+  }
+
+  fn is_empty(&self) -> bool {
+    self.pos + 8 > self.buf.len()
+  }
+
+  fn _u32_at(&self, pos: usize) -> u32 {
+    if self.big_endian {
+      BigEndian::read_u32(&self.buf[pos .. pos + 4])
+    } else {
+      LittleEndian::read_u32(&self.buf[pos .. pos + 4])
+    }
+  }
+
+  /// Reads one data element, returning its type and payload.
+  fn read_element(&mut self) -> Result<(u32, &'a [u8]), Error> {
+    if self.pos + 8 > self.buf.len() {
+      return Err(format_error("truncated element tag"));
+    }
+    let word = self._u32_at(self.pos);
+    if (word >> 16) != 0 {
+      // Small data element: type, size and up to 4 bytes of data in 8 bytes.
+      let ty = word & 0xffff;
+      let nbytes = (word >> 16) as usize;
+      if nbytes > 4 {
+        return Err(format_error("bad small element size"));
+      }
+      let data = &self.buf[self.pos + 4 .. self.pos + 4 + nbytes];
+      self.pos += 8;
+      return Ok((ty, data));
+    }
+    let ty = word;
+    let nbytes = self._u32_at(self.pos + 4) as usize;
+    let start = self.pos + 8;
+    if start + nbytes > self.buf.len() {
+      return Err(format_error("truncated element data"));
+    }
+    let data = &self.buf[start .. start + nbytes];
+    // Compressed elements are not padded.
+    self.pos = if ty == MI_COMPRESSED { start + nbytes } else { start + pad8(nbytes) };
+    Ok((ty, data))
+  }
+
+  fn read_i32s(&mut self) -> Result<Vec<i32>, Error> {
+    let (ty, data) = self.read_element()?;
+    if ty != MI_INT32 {
+      return Err(format_error("expected an int32 element"));
+    }
+    let mut xs = Vec::with_capacity(data.len() / 4);
+    for i in 0 .. data.len() / 4 {
+      let x = if self.big_endian {
+        BigEndian::read_i32(&data[4 * i .. 4 * (i + 1)])
+      } else {
+        LittleEndian::read_i32(&data[4 * i .. 4 * (i + 1)])
+      };
+      xs.push(x);
+    }
+    Ok(xs)
+  }
+}
+
+fn decode_numeric(ty: u32, data: &[u8], big_endian: bool) -> Result<Vec<f64>, Error> {
+  macro_rules! read {
+    ($sz:expr, $read:ident) => {{
+      let mut xs = Vec::with_capacity(data.len() / $sz);
+      for i in 0 .. data.len() / $sz {
+        let buf = &data[$sz * i .. $sz * (i + 1)];
+        let x = if big_endian { BigEndian::$read(buf) } else { LittleEndian::$read(buf) };
+        xs.push(x as f64);
+      }
+      xs
+    }};
+  }
+  let xs = match ty {
+    MI_INT8 => data.iter().map(|&x| x as i8 as f64).collect(),
+    MI_UINT8 => data.iter().map(|&x| x as f64).collect(),
+    MI_INT16 => read!(2, read_i16),
+    MI_UINT16 => read!(2, read_u16),
+    MI_INT32 => read!(4, read_i32),
+    MI_UINT32 => read!(4, read_u32),
+    MI_SINGLE => read!(4, read_f32),
+    MI_DOUBLE => read!(8, read_f64),
+    MI_INT64 => read!(8, read_i64),
+    MI_UINT64 => read!(8, read_u64),
+    _ => return Err(format_error(&format!("unsupported numeric element type: {}", ty))),
+  };
+  Ok(xs)
+}
+
+fn decode_chars(ty: u32, data: &[u8], dims: &[usize], big_endian: bool) -> Result<String, Error> {
+  let chars: Vec<char> = match ty {
+    MI_UINT16 | MI_UTF16 => {
+      let mut units = Vec::with_capacity(data.len() / 2);
+      for i in 0 .. data.len() / 2 {
+        let buf = &data[2 * i .. 2 * (i + 1)];
+        units.push(if big_endian { BigEndian::read_u16(buf) } else { LittleEndian::read_u16(buf) });
+      }
+      String::from_utf16_lossy(&units).chars().collect()
+    }
+    MI_UINT8 | MI_INT8 | MI_UTF8 => {
+      String::from_utf8_lossy(data).chars().collect()
+    }
+    _ => return Err(format_error(&format!("unsupported char element type: {}", ty))),
+  };
+  // Char arrays are column-major; join multiple rows with newlines.
+  let rows = if dims.len() >= 1 { dims[0] } else { 1 };
+  if rows <= 1 || chars.len() % rows != 0 {
+    return Ok(chars.into_iter().collect());
+  }
+  let cols = chars.len() / rows;
+  let mut s = String::with_capacity(chars.len() + rows);
+  for r in 0 .. rows {
+    if r > 0 {
+      s.push('\n');
+    }
+    for c in 0 .. cols {
+      s.push(chars[r + rows * c]);
+    }
+  }
+  Ok(s)
+}
+
+/// Parses the payload of a `miMATRIX` element into its name and value.
+fn parse_matrix(data: &[u8], big_endian: bool) -> Result<(String, MatValue), Error> {
+  if data.is_empty() {
+    return Ok((String::new(), MatValue::Empty));
+  }
+  let mut reader = MatReader::new(data, big_endian);
+  let (flags_ty, flags) = reader.read_element()?;
+  if flags_ty != MI_UINT32 || flags.len() < 4 {
+    return Err(format_error("bad array flags"));
+  }
+  let class = if big_endian {
+    BigEndian::read_u32(&flags[ .. 4])
+  } else {
+    LittleEndian::read_u32(&flags[ .. 4])
+  } & 0xff;
+  let dims: Vec<usize> = reader.read_i32s()?.into_iter().map(|d| d as usize).collect();
+  let num_elems = dims.iter().fold(1, |n, &d| n * d);
+  let (_, name) = reader.read_element()?;
+  let name = String::from_utf8_lossy(name).into_owned();
+  let value = match class {
+    MX_CELL_CLASS => {
+      let mut elems = Vec::with_capacity(num_elems);
+      for _ in 0 .. num_elems {
+        let (ty, elem_data) = reader.read_element()?;
+        if ty != MI_MATRIX {
+          return Err(format_error("expected a matrix cell element"));
+        }
+        elems.push(parse_matrix(elem_data, big_endian)?.1);
+      }
+      MatValue::Cell{dims: dims, elems: elems}
+    }
+    MX_STRUCT_CLASS => {
+      let field_name_len = match reader.read_i32s()?.get(0) {
+        Some(&len) if len > 0 => len as usize,
+        _ => return Err(format_error("bad struct field name length")),
+      };
+      let (_, field_names) = reader.read_element()?;
+      let mut fields = Vec::with_capacity(field_names.len() / field_name_len);
+      for chunk in field_names.chunks(field_name_len) {
+        let end = chunk.iter().position(|&b| b == 0).unwrap_or(chunk.len());
+        fields.push(String::from_utf8_lossy(&chunk[ .. end]).into_owned());
+      }
+      let mut values = Vec::with_capacity(num_elems * fields.len());
+      for _ in 0 .. num_elems * fields.len() {
+        let (ty, field_data) = reader.read_element()?;
+        if ty != MI_MATRIX {
+          return Err(format_error("expected a matrix struct field"));
+        }
+        values.push(parse_matrix(field_data, big_endian)?.1);
+      }
+      MatValue::Struct{dims: dims, fields: fields, values: values}
+    }
+    MX_CHAR_CLASS => {
+      if reader.is_empty() {
+        MatValue::Char{dims: dims, data: String::new()}
+      } else {
+        let (ty, char_data) = reader.read_element()?;
+        let s = decode_chars(ty, char_data, &dims, big_endian)?;
+        MatValue::Char{dims: dims, data: s}
+      }
+    }
+    MX_DOUBLE_CLASS ..= MX_UINT64_CLASS => {
+      if reader.is_empty() {
+        MatValue::Numeric{dims: dims, data: vec![]}
+      } else {
+        // Only the real part is read.
+        let (ty, num_data) = reader.read_element()?;
+        let xs = decode_numeric(ty, num_data, big_endian)?;
+        MatValue::Numeric{dims: dims, data: xs}
+      }
+    }
+    _ => return Err(format_error(&format!("unsupported array class: {}", class))),
+  };
+  Ok((name, value))
+}
+
+/// Loads all variables of a level 5 MAT-file.
+pub fn load_mat_file(path: PathBuf) -> Result<HashMap<String, MatValue>, Error> {
+  let mut file = open_file(&path)?;
+  let mut buf = Vec::new();
+  file.read_to_end(&mut buf)?;
+  parse_mat_buf(&buf)
+}
+
+fn parse_mat_buf(buf: &[u8]) -> Result<HashMap<String, MatValue>, Error> {
+  if buf.len() < 128 {
+    return Err(format_error("truncated header"));
+  }
+  let big_endian = if &buf[126 .. 128] == b"IM" {
+    false
+  } else if &buf[126 .. 128] == b"MI" {
+    true
+  } else {
+    return Err(format_error("not a level 5 MAT-file"));
+  };
+  let mut vars = HashMap::new();
+  let mut reader = MatReader::new(&buf[128 .. ], big_endian);
+  while !reader.is_empty() {
+    let (ty, data) = reader.read_element()?;
+    let inflated;
+    let (ty, data) = if ty == MI_COMPRESSED {
+      let mut decoder = ZlibDecoder::new(data);
+      let mut out = Vec::new();
+      if let Err(e) = decoder.read_to_end(&mut out) {
+        return Err(format_error(&format!("failed to inflate element: {}", e)));
+      }
+      inflated = out;
+      let mut inner = MatReader::new(&inflated, big_endian);
+      inner.read_element()?
+    } else {
+      (ty, data)
+    };
+    if ty != MI_MATRIX {
+      continue;
+    }
+    let (name, value) = parse_matrix(data, big_endian)?;
+    vars.insert(name, value);
+  }
+  Ok(vars)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use byteorder::{WriteBytesExt};
+  use flate2::{Compression};
+  use flate2::write::{ZlibEncoder};
+
+  use std::io::{Write};
+
+  fn element(ty: u32, data: &[u8]) -> Vec<u8> {
+    let mut buf = vec![];
+    buf.write_u32::<LittleEndian>(ty).unwrap();
+    buf.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+    buf.extend_from_slice(data);
+    buf.resize(8 + pad8(data.len()), 0);
+    buf
+  }
+
+  fn small_element(ty: u32, data: &[u8]) -> Vec<u8> {
+    assert!(data.len() <= 4);
+    let mut buf = vec![];
+    buf.write_u32::<LittleEndian>(((data.len() as u32) << 16) | ty).unwrap();
+    buf.extend_from_slice(data);
+    buf.resize(8, 0);
+    buf
+  }
+
+  fn matrix(class: u32, dims: &[i32], name: &str, body: &[u8]) -> Vec<u8> {
+    let mut flags = vec![];
+    flags.write_u32::<LittleEndian>(class).unwrap();
+    flags.write_u32::<LittleEndian>(0).unwrap();
+    let mut dims_buf = vec![];
+    for &d in dims {
+      dims_buf.write_i32::<LittleEndian>(d).unwrap();
+    }
+    let mut payload = element(MI_UINT32, &flags);
+    payload.extend(element(MI_INT32, &dims_buf));
+    payload.extend(element(MI_INT8, name.as_bytes()));
+    payload.extend_from_slice(body);
+    element(MI_MATRIX, &payload)
+  }
+
+  fn double(x: f64) -> Vec<u8> {
+    let mut data = vec![];
+    data.write_f64::<LittleEndian>(x).unwrap();
+    matrix(MX_DOUBLE_CLASS, &[1, 1], "", &element(MI_DOUBLE, &data))
+  }
+
+  fn chars(s: &str) -> Vec<u8> {
+    let mut data = vec![];
+    for u in s.encode_utf16() {
+      data.write_u16::<LittleEndian>(u).unwrap();
+    }
+    matrix(MX_CHAR_CLASS, &[1, s.len() as i32], "", &element(MI_UINT16, &data))
+  }
+
+  fn synsets_mat() -> Vec<u8> {
+    let mut buf = vec![b' '; 116];
+    buf[ .. 10].copy_from_slice(b"MATLAB 5.0");
+    buf.extend_from_slice(&[0; 8]);
+    buf.extend_from_slice(&[0x00, 0x01]);
+    buf.extend_from_slice(b"IM");
+    // A 1x2 struct array with fields `ID` and `WNID`, stored compressed.
+    let mut field_names = vec![0; 64];
+    field_names[ .. 2].copy_from_slice(b"ID");
+    field_names[32 .. 36].copy_from_slice(b"WNID");
+    let mut field_name_len = vec![];
+    field_name_len.write_i32::<LittleEndian>(32).unwrap();
+    let mut body = small_element(MI_INT32, &field_name_len);
+    body.extend(element(MI_INT8, &field_names));
+    body.extend(double(1.0));
+    body.extend(chars("n01440764"));
+    body.extend(double(2.0));
+    body.extend(chars("n01443537"));
+    let synsets = matrix(MX_STRUCT_CLASS, &[1, 2], "synsets", &body);
+    let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+    encoder.write_all(&synsets).unwrap();
+    let compressed = encoder.finish().unwrap();
+    buf.write_u32::<LittleEndian>(MI_COMPRESSED).unwrap();
+    buf.write_u32::<LittleEndian>(compressed.len() as u32).unwrap();
+    buf.extend(compressed);
+    // An uncompressed variable right after the unpadded compressed one.
+    let mut data = vec![];
+    data.write_f64::<LittleEndian>(2.0).unwrap();
+    buf.extend(matrix(MX_DOUBLE_CLASS, &[1, 1], "n", &element(MI_DOUBLE, &data)));
+    buf
+  }
+
+  #[test]
+  fn test_parse_compressed_struct_array() {
+    let vars = parse_mat_buf(&synsets_mat()).unwrap();
+    assert_eq!(vars.len(), 2);
+    let synsets = &vars["synsets"];
+    assert_eq!(synsets.num_elems(), 2);
+    assert_eq!(synsets.field(0, "ID").and_then(|v| v.as_f64()), Some(1.0));
+    assert_eq!(synsets.field(0, "WNID").and_then(|v| v.as_str()), Some("n01440764"));
+    assert_eq!(synsets.field(1, "ID").and_then(|v| v.as_f64()), Some(2.0));
+    assert_eq!(synsets.field(1, "WNID").and_then(|v| v.as_str()), Some("n01443537"));
+    assert!(synsets.field(0, "words").is_none());
+    assert_eq!(vars["n"].as_f64(), Some(2.0));
+  }
+
+  #[test]
+  fn test_reject_bad_header() {
+    let mut buf = synsets_mat();
+    buf[126 .. 128].copy_from_slice(b"XX");
+    assert!(parse_mat_buf(&buf).is_err());
+  }
+}
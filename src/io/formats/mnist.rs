@@ -15,6 +15,7 @@ limitations under the License.
 */
 
 use io::*;
+use io::formats::idx::{IdxArray, IdxDtype};
 
 use densearray::prelude::*;
 use sharedmem::{MemoryMap, SharedMem};
@@ -23,58 +24,123 @@ use byteorder::{ReadBytesExt, BigEndian};
 use std::fs::{File};
 use std::path::{PathBuf};
 
-pub fn mmap_mnist_idx_file(mut file: File) -> (usize, Vec<usize>, MemoryMap<u8>) {
-  let magic: u32 = file.read_u32::<BigEndian>().unwrap();
+/// Mmaps an unsigned byte IDX file; see `IdxArray` for other element types.
+pub fn mmap_mnist_idx_file(file: File) -> (usize, Vec<usize>, MemoryMap<u8>) {
+  match try_mmap_mnist_idx_file(file) {
+    Ok(x) => x,
+    Err(e) => panic!("failed to mmap mnist idx file: {}", e),
+  }
+}
+
+pub fn try_mmap_mnist_idx_file(mut file: File) -> Result<(usize, Vec<usize>, MemoryMap<u8>), Error> {
+  let file_sz = file.metadata()?.len() as usize;
+  let magic: u32 = file.read_u32::<BigEndian>()?;
   let magic2 = (magic >> 8) as u8;
   let magic3 = (magic >> 0) as u8;
-  assert_eq!(magic2, 0x08);
+  if (magic >> 16) != 0 || magic2 != 0x08 {
+    return Err(Error::Format(format!("mnist idx: bad magic number: 0x{:08x}", magic)));
+  }
   let ndims = magic3 as usize;
+  if ndims == 0 {
+    return Err(Error::Format("mnist idx: zero dimensions".to_owned()));
+  }
   let mut dims = vec![];
   for _ in 0 .. ndims {
-    dims.push(file.read_u32::<BigEndian>().unwrap() as usize);
+    dims.push(file.read_u32::<BigEndian>()? as usize);
   }
   let n = dims[0] as usize;
   let mut frame_size = 1;
   for d in 1 .. ndims {
     frame_size *= dims[d] as usize;
   }
-  let buf = match MemoryMap::open_with_offset(file, (1 + ndims) * 4, frame_size * n) {
+  let header_sz = (1 + ndims) * 4;
+  if header_sz + frame_size * n > file_sz {
+    return Err(Error::Format(format!("mnist idx: truncated file: expected {} bytes, got {}", header_sz + frame_size * n, file_sz)));
+  }
+  let buf = match MemoryMap::open_with_offset(file, header_sz, frame_size * n) {
     Ok(buf) => buf,
-    Err(e) => panic!("failed to mmap buffer: {:?}", e),
+    Err(e) => return Err(Error::Mmap(format!("{:?}", e))),
   };
   let mut colmaj_dims = vec![];
   for d in (1 .. ndims).rev() {
     colmaj_dims.push(dims[d]);
   }
-  (n, colmaj_dims, buf)
+  Ok((n, colmaj_dims, buf))
 }
 
+/// MNIST-format data: IDX frames of unsigned bytes with shape `(n, rows,
+/// cols)` and IDX scalar integer labels. This also covers Fashion-MNIST and
+/// KMNIST; EMNIST additionally stores its frames transposed, see
+/// `open_emnist`.
 #[derive(Clone)]
 pub struct MnistData {
   len:      usize,
-  frame_sz: usize,
-  frame_d:  Vec<usize>,
-  frames_m: SharedMem<u8>,
-  labels_m: SharedMem<u8>,
+  frame_d:  (usize, usize),
+  transpose:    bool,
+  label_offset: i64,
+  frames:   IdxArray,
+  labels:   IdxArray,
 }
 
 impl MnistData {
   pub fn open(frames_path: PathBuf, labels_path: PathBuf) -> MnistData {
-    let frames_file = File::open(&frames_path).unwrap();
-    let labels_file = File::open(&labels_path).unwrap();
-    let (f_n, frame_dim, frames_mmap) = mmap_mnist_idx_file(frames_file);
-    let (l_n, label_dim, labels_mmap) = mmap_mnist_idx_file(labels_file);
-    assert_eq!(f_n, l_n);
-    assert_eq!(2, frame_dim.len());
-    assert_eq!(0, label_dim.len());
-    //println!("DEBUG: mnist: {} {:?} {:?}", f_n, frame_dim, label_dim);
-    MnistData{
-      len:      f_n,
-      frame_sz: frame_dim[0] * frame_dim[1],
-      frame_d:  frame_dim,
-      frames_m: SharedMem::new(frames_mmap),
-      labels_m: SharedMem::new(labels_mmap),
+    match Self::try_open(frames_path, labels_path) {
+      Ok(data) => data,
+      Err(e) => panic!("failed to open mnist data: {}", e),
+    }
+  }
+
+  pub fn try_open(frames_path: PathBuf, labels_path: PathBuf) -> Result<MnistData, Error> {
+    let frames = IdxArray::try_open(frames_path)?;
+    let labels = IdxArray::try_open(labels_path)?;
+    if frames.len() != labels.len() {
+      return Err(Error::Format(format!("mnist: mismatched frame and label counts: {} {}", frames.len(), labels.len())));
+    }
+    if frames.dtype() != IdxDtype::U8 {
+      return Err(Error::Format(format!("mnist: expected u8 frames, got {:?}", frames.dtype())));
+    }
+    if frames.item_dims().len() != 2 {
+      return Err(Error::Format(format!("mnist: expected 2-d frames, got {:?}", frames.item_dims())));
+    }
+    if labels.item_dims().len() != 0 || !labels.dtype().is_integer() {
+      return Err(Error::Format(format!("mnist: expected scalar integer labels, got {:?} {:?}", labels.dtype(), labels.item_dims())));
     }
+    // Frame dims are column-major, i.e. (cols, rows).
+    let frame_d = (frames.item_dims()[1], frames.item_dims()[0]);
+    Ok(MnistData{
+      len:      frames.len(),
+      frame_d:  frame_d,
+      transpose:    false,
+      label_offset: 0,
+      frames:   frames,
+      labels:   labels,
+    })
+  }
+
+  /// Opens EMNIST data, whose frames are stored transposed. `label_offset`
+  /// is added to every label; the letters split numbers its classes from 1,
+  /// so pass -1 there and 0 for the other splits.
+  pub fn open_emnist(frames_path: PathBuf, labels_path: PathBuf, label_offset: i64) -> MnistData {
+    match Self::try_open_emnist(frames_path, labels_path, label_offset) {
+      Ok(data) => data,
+      Err(e) => panic!("failed to open emnist data: {}", e),
+    }
+  }
+
+  pub fn try_open_emnist(frames_path: PathBuf, labels_path: PathBuf, label_offset: i64) -> Result<MnistData, Error> {
+    Ok(Self::try_open(frames_path, labels_path)?.transposed().with_label_offset(label_offset))
+  }
+
+  /// Transposes each frame on access.
+  pub fn transposed(mut self) -> MnistData {
+    self.transpose = !self.transpose;
+    self
+  }
+
+  /// Adds an offset to every label.
+  pub fn with_label_offset(mut self, offset: i64) -> MnistData {
+    self.label_offset += offset;
+    self
   }
 }
 
@@ -86,9 +152,21 @@ impl IndexedData for MnistData {
   }
 
   fn get(&mut self, idx: usize) -> Self::Item {
-    let frame_dim = (self.frame_d[0], self.frame_d[1]);
-    let frame_buf = self.frames_m.slice_v2(idx * self.frame_sz .. (idx+1) * self.frame_sz);
-    let label = self.labels_m[idx] as u32;
-    (Array2d::from_storage(frame_dim, frame_buf), label)
+    let frame_buf = self.frames.get_bytes(idx);
+    let label = self.labels.get_scalar_i64(idx) + self.label_offset;
+    assert!(label >= 0, "mnist: negative label: {}", label);
+    if !self.transpose {
+      return (Array2d::from_storage(self.frame_d, frame_buf), label as u32);
+    }
+    let (cols, rows) = self.frame_d;
+    let mut t_buf = Vec::with_capacity(rows * cols);
+    for c in 0 .. cols {
+      for r in 0 .. rows {
+        t_buf.push(frame_buf[r * cols + c]);
+      }
+    }
+    (Array2d::from_storage((rows, cols), SharedMem::new(t_buf)), label as u32)
   }
 }
+
+impl_stateless!(MnistData);
@@ -16,7 +16,12 @@ limitations under the License.
 
 pub mod cifar;
 pub mod fake;
+pub mod idx;
+pub mod imagefolder;
 pub mod imagenet;
+pub mod matlab;
 pub mod mnist;
+pub mod pickle;
 pub mod ptb;
+pub mod tarshard;
 pub mod varraydb;
@@ -0,0 +1,507 @@
+/*
+Copyright 2017 the superlearn authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! A minimal decoder for Python pickles (protocols 0-2 binary opcodes, plus
+//! the framing opcodes of protocol 4), sufficient for dicts of lists and
+//! numpy arrays as found in the CIFAR python batches.
+//!
+//! Objects are not instantiated; calls (`REDUCE`, `NEWOBJ`) and `BUILD` are
+//! kept as nodes in the value tree. The memo stores a snapshot of a value at
+//! the time it is memoized, so later in-place mutations (e.g. `APPENDS` on a
+//! memoized list) are not reflected in memo lookups. Byte strings are
+//! reference counted, so memoizing values that hold large arrays does not
+//! copy their data.
+
+use io::*;
+
+use byteorder::{ByteOrder, BigEndian, LittleEndian};
+
+use std::collections::{HashMap};
+use std::io::{Read};
+use std::path::{PathBuf};
+use std::rc::{Rc};
+
+#[derive(Clone, Debug)]
+pub enum PickleValue {
+  None,
+  Bool(bool),
+  Int(i64),
+  Float(f64),
+  /// Byte strings, including Python 2 `str`.
+  Bytes(Rc<Vec<u8>>),
+  Str(String),
+  List(Vec<PickleValue>),
+  Tuple(Vec<PickleValue>),
+  Dict(Vec<(PickleValue, PickleValue)>),
+  Global(String, String),
+  Reduce(Box<PickleValue>, Box<PickleValue>),
+  Build(Box<PickleValue>, Box<PickleValue>),
+}
+
+impl PickleValue {
+  pub fn as_i64(&self) -> Option<i64> {
+    match *self {
+      PickleValue::Int(x) => Some(x),
+      PickleValue::Bool(x) => Some(x as i64),
+      _ => None,
+    }
+  }
+
+  pub fn as_list(&self) -> Option<&[PickleValue]> {
+    match *self {
+      PickleValue::List(ref xs) | PickleValue::Tuple(ref xs) => Some(xs),
+      _ => None,
+    }
+  }
+
+  /// The contents of a byte string. Python 3 pickles of `bytes` at protocol
+  /// 2 go through `_codecs.encode(str, 'latin1')`, which is undone here.
+  pub fn as_bytes(&self) -> Option<Rc<Vec<u8>>> {
+    match *self {
+      PickleValue::Bytes(ref buf) => Some(buf.clone()),
+      PickleValue::Reduce(ref func, ref args) => {
+        match (&**func, args.as_list()) {
+          (&PickleValue::Global(ref module, ref name), Some(args))
+              if module == "_codecs" && name == "encode" && args.len() == 2 =>
+          {
+            match args[0] {
+              PickleValue::Str(ref s) => {
+                let mut buf = Vec::with_capacity(s.len());
+                for c in s.chars() {
+                  if c as u32 > 0xff {
+                    return None;
+                  }
+                  buf.push(c as u32 as u8);
+                }
+                Some(Rc::new(buf))
+              }
+              _ => None,
+            }
+          }
+          _ => None,
+        }
+      }
+      _ => None,
+    }
+  }
+
+  /// Looks up a dict entry by a `str` or byte string key.
+  pub fn get(&self, key: &str) -> Option<&PickleValue> {
+    match *self {
+      PickleValue::Dict(ref entries) => {
+        for &(ref k, ref v) in entries.iter() {
+          let matches = match *k {
+            PickleValue::Str(ref s) => s == key,
+            PickleValue::Bytes(ref b) => &b[ .. ] == key.as_bytes(),
+            _ => false,
+          };
+          if matches {
+            return Some(v);
+          }
+        }
+        None
+      }
+      _ => None,
+    }
+  }
+
+  /// Decodes a pickled `numpy.ndarray` into its shape, dtype string (e.g.
+  /// `"u1"`, `"<f4"`) and raw C-order data.
+  pub fn as_ndarray(&self) -> Option<(Vec<usize>, String, Rc<Vec<u8>>)> {
+    let (obj, state) = match *self {
+      PickleValue::Build(ref obj, ref state) => (obj, state),
+      _ => return None,
+    };
+    match **obj {
+      PickleValue::Reduce(ref func, _) => {
+        match **func {
+          PickleValue::Global(ref module, ref name)
+              if module.starts_with("numpy") && name == "_reconstruct" => {}
+          _ => return None,
+        }
+      }
+      _ => return None,
+    }
+    // State is `(version, shape, dtype, is_fortran, rawdata)`.
+    let state = match state.as_list() {
+      Some(state) if state.len() == 5 => state,
+      _ => return None,
+    };
+    let mut shape = vec![];
+    for d in state[1].as_list().unwrap_or(&[]).iter() {
+      match d.as_i64() {
+        Some(d) if d >= 0 => shape.push(d as usize),
+        _ => return None,
+      }
+    }
+    if state[3].as_i64().unwrap_or(0) != 0 {
+      return None;
+    }
+    let dtype = match ndarray_dtype(&state[2]) {
+      None => return None,
+      Some(dtype) => dtype,
+    };
+    let data = match state[4].as_bytes() {
+      None => return None,
+      Some(data) => data,
+    };
+    Some((shape, dtype, data))
+  }
+}
+
+fn ndarray_dtype(value: &PickleValue) -> Option<String> {
+  let (obj, endian) = match *value {
+    PickleValue::Build(ref obj, ref state) => {
+      // Dtype state is `(version, byteorder, ...)`.
+      let endian = match state.as_list().and_then(|s| s.get(1)) {
+        Some(&PickleValue::Str(ref s)) => s.clone(),
+        Some(&PickleValue::Bytes(ref b)) => String::from_utf8_lossy(b).into_owned(),
+        _ => String::new(),
+      };
+      (&**obj, endian)
+    }
+    ref obj => (obj, String::new()),
+  };
+  match *obj {
+    PickleValue::Reduce(_, ref args) => {
+      let name = match args.as_list().and_then(|a| a.get(0)) {
+        Some(&PickleValue::Str(ref s)) => s.clone(),
+        Some(&PickleValue::Bytes(ref b)) => String::from_utf8_lossy(b).into_owned(),
+        _ => return None,
+      };
+      if endian == "|" || endian == "=" || endian.is_empty() {
+        Some(name)
+      } else {
+        Some(format!("{}{}", endian, name))
+      }
+    }
+    _ => None,
+  }
+}
+
+fn format_error(msg: &str) -> Error {
+  Error::Format(format!("pickle: {}", msg))
+}
+
+struct Unpickler<'a> {
+  buf:      &'a [u8],
+  pos:      usize,
+  stack:    Vec<PickleValue>,
+  marks:    Vec<usize>,
+  memo:     HashMap<u32, PickleValue>,
+}
+
+impl<'a> Unpickler<'a> {
+  fn read(&mut self, n: usize) -> Result<&'a [u8], Error> {
+    if self.pos + n > self.buf.len() {
+      return Err(format_error("unexpected end of data"));
+    }
+    let buf = self.buf;
+    let data = &buf[self.pos .. self.pos + n];
+    self.pos += n;
+    Ok(data)
+  }
+
+  fn read_u8(&mut self) -> Result<u8, Error> {
+    Ok(self.read(1)?[0])
+  }
+
+  fn read_u32(&mut self) -> Result<u32, Error> {
+    Ok(LittleEndian::read_u32(self.read(4)?))
+  }
+
+  fn read_u64(&mut self) -> Result<u64, Error> {
+    Ok(LittleEndian::read_u64(self.read(8)?))
+  }
+
+  fn read_line(&mut self) -> Result<String, Error> {
+    let end = match self.buf[self.pos .. ].iter().position(|&b| b == b'\n') {
+      None => return Err(format_error("unterminated line")),
+      Some(end) => self.pos + end,
+    };
+    let line = String::from_utf8_lossy(&self.buf[self.pos .. end]).into_owned();
+    self.pos = end + 1;
+    Ok(line)
+  }
+
+  fn read_str(&mut self, n: usize) -> Result<String, Error> {
+    match String::from_utf8(self.read(n)?.to_owned()) {
+      Ok(s) => Ok(s),
+      Err(_) => Err(format_error("invalid utf-8 string")),
+    }
+  }
+
+  fn pop(&mut self) -> Result<PickleValue, Error> {
+    match self.stack.pop() {
+      None => Err(format_error("stack underflow")),
+      Some(x) => Ok(x),
+    }
+  }
+
+  fn pop_mark(&mut self) -> Result<Vec<PickleValue>, Error> {
+    let mark = match self.marks.pop() {
+      None => return Err(format_error("missing mark")),
+      Some(mark) => mark,
+    };
+    if mark > self.stack.len() {
+      return Err(format_error("stack underflow"));
+    }
+    Ok(self.stack.split_off(mark))
+  }
+
+  fn top(&mut self) -> Result<&mut PickleValue, Error> {
+    match self.stack.last_mut() {
+      None => Err(format_error("stack underflow")),
+      Some(x) => Ok(x),
+    }
+  }
+
+  fn memo_put(&mut self, key: u32) -> Result<(), Error> {
+    let value = self.top()?.clone();
+    self.memo.insert(key, value);
+    Ok(())
+  }
+
+  fn memo_get(&mut self, key: u32) -> Result<(), Error> {
+    let value = match self.memo.get(&key) {
+      None => return Err(format_error(&format!("missing memo key: {}", key))),
+      Some(value) => value.clone(),
+    };
+    self.stack.push(value);
+    Ok(())
+  }
+
+  fn append(&mut self, items: Vec<PickleValue>) -> Result<(), Error> {
+    match *self.top()? {
+      PickleValue::List(ref mut xs) => {
+        xs.extend(items);
+        Ok(())
+      }
+      _ => Err(format_error("append to a non-list")),
+    }
+  }
+
+  fn set_items(&mut self, items: Vec<PickleValue>) -> Result<(), Error> {
+    if items.len() % 2 != 0 {
+      return Err(format_error("odd number of dict items"));
+    }
+    match *self.top()? {
+      PickleValue::Dict(ref mut entries) => {
+        let mut items = items.into_iter();
+        while let (Some(k), Some(v)) = (items.next(), items.next()) {
+          entries.push((k, v));
+        }
+        Ok(())
+      }
+      _ => Err(format_error("setitem on a non-dict")),
+    }
+  }
+
+  fn run(&mut self) -> Result<PickleValue, Error> {
+    loop {
+      let op = self.read_u8()?;
+      match op {
+        // PROTO
+        0x80 => { self.read_u8()?; }
+        // FRAME
+        0x95 => { self.read_u64()?; }
+        // STOP
+        b'.' => return self.pop(),
+        // MARK
+        b'(' => { let n = self.stack.len(); self.marks.push(n); }
+        // POP, POP_MARK
+        b'0' => { self.pop()?; }
+        b'1' => { self.pop_mark()?; }
+        // DUP
+        b'2' => { let x = self.top()?.clone(); self.stack.push(x); }
+        b'N' => self.stack.push(PickleValue::None),
+        0x88 => self.stack.push(PickleValue::Bool(true)),
+        0x89 => self.stack.push(PickleValue::Bool(false)),
+        // BININT, BININT1, BININT2
+        b'J' => { let x = self.read_u32()? as i32 as i64; self.stack.push(PickleValue::Int(x)); }
+        b'K' => { let x = self.read_u8()? as i64; self.stack.push(PickleValue::Int(x)); }
+        b'M' => { let x = LittleEndian::read_u16(self.read(2)?) as i64; self.stack.push(PickleValue::Int(x)); }
+        // INT, LONG (text)
+        b'I' | b'L' => {
+          let line = self.read_line()?;
+          let line = line.trim_end_matches('L');
+          let x = match line {
+            "00" => PickleValue::Bool(false),
+            "01" => PickleValue::Bool(true),
+            _ => match line.parse::<i64>() {
+              Ok(x) => PickleValue::Int(x),
+              Err(_) => return Err(format_error(&format!("bad int literal: {}", line))),
+            },
+          };
+          self.stack.push(x);
+        }
+        // LONG1
+        0x8a => {
+          let n = self.read_u8()? as usize;
+          let data = self.read(n)?;
+          if n > 8 {
+            return Err(format_error("long integer too large"));
+          }
+          let mut x: i64 = 0;
+          for (i, &b) in data.iter().enumerate() {
+            x |= (b as i64) << (8 * i);
+          }
+          if n > 0 && n < 8 && data[n - 1] & 0x80 != 0 {
+            x -= 1_i64 << (8 * n);
+          }
+          self.stack.push(PickleValue::Int(x));
+        }
+        // BINFLOAT
+        b'G' => { let x = BigEndian::read_f64(self.read(8)?); self.stack.push(PickleValue::Float(x)); }
+        // SHORT_BINSTRING, BINSTRING (Python 2 str)
+        b'U' => { let n = self.read_u8()? as usize; let b = self.read(n)?.to_owned(); self.stack.push(PickleValue::Bytes(Rc::new(b))); }
+        b'T' => { let n = self.read_u32()? as usize; let b = self.read(n)?.to_owned(); self.stack.push(PickleValue::Bytes(Rc::new(b))); }
+        // SHORT_BINBYTES, BINBYTES, BINBYTES8
+        b'C' => { let n = self.read_u8()? as usize; let b = self.read(n)?.to_owned(); self.stack.push(PickleValue::Bytes(Rc::new(b))); }
+        b'B' => { let n = self.read_u32()? as usize; let b = self.read(n)?.to_owned(); self.stack.push(PickleValue::Bytes(Rc::new(b))); }
+        0x8e => { let n = self.read_u64()? as usize; let b = self.read(n)?.to_owned(); self.stack.push(PickleValue::Bytes(Rc::new(b))); }
+        // SHORT_BINUNICODE, BINUNICODE, BINUNICODE8
+        0x8c => { let n = self.read_u8()? as usize; let s = self.read_str(n)?; self.stack.push(PickleValue::Str(s)); }
+        b'X' => { let n = self.read_u32()? as usize; let s = self.read_str(n)?; self.stack.push(PickleValue::Str(s)); }
+        0x8d => { let n = self.read_u64()? as usize; let s = self.read_str(n)?; self.stack.push(PickleValue::Str(s)); }
+        b']' => self.stack.push(PickleValue::List(vec![])),
+        b')' => self.stack.push(PickleValue::Tuple(vec![])),
+        b'}' => self.stack.push(PickleValue::Dict(vec![])),
+        // LIST, TUPLE, DICT
+        b'l' => { let xs = self.pop_mark()?; self.stack.push(PickleValue::List(xs)); }
+        b't' => { let xs = self.pop_mark()?; self.stack.push(PickleValue::Tuple(xs)); }
+        b'd' => {
+          let xs = self.pop_mark()?;
+          self.stack.push(PickleValue::Dict(vec![]));
+          self.set_items(xs)?;
+        }
+        // TUPLE1, TUPLE2, TUPLE3
+        0x85 ..= 0x87 => {
+          let n = (op - 0x84) as usize;
+          if n > self.stack.len() {
+            return Err(format_error("stack underflow"));
+          }
+          let at = self.stack.len() - n;
+          let xs = self.stack.split_off(at);
+          self.stack.push(PickleValue::Tuple(xs));
+        }
+        // APPEND, APPENDS
+        b'a' => { let x = self.pop()?; self.append(vec![x])?; }
+        b'e' => { let xs = self.pop_mark()?; self.append(xs)?; }
+        // SETITEM, SETITEMS
+        b's' => {
+          let v = self.pop()?;
+          let k = self.pop()?;
+          self.set_items(vec![k, v])?;
+        }
+        b'u' => { let xs = self.pop_mark()?; self.set_items(xs)?; }
+        // BINPUT, LONG_BINPUT, MEMOIZE
+        b'q' => { let key = self.read_u8()? as u32; self.memo_put(key)?; }
+        b'r' => { let key = self.read_u32()?; self.memo_put(key)?; }
+        0x94 => { let key = self.memo.len() as u32; self.memo_put(key)?; }
+        // BINGET, LONG_BINGET
+        b'h' => { let key = self.read_u8()? as u32; self.memo_get(key)?; }
+        b'j' => { let key = self.read_u32()?; self.memo_get(key)?; }
+        // GLOBAL, STACK_GLOBAL
+        b'c' => {
+          let module = self.read_line()?;
+          let name = self.read_line()?;
+          self.stack.push(PickleValue::Global(module, name));
+        }
+        0x93 => {
+          let name = self.pop()?;
+          let module = self.pop()?;
+          match (module, name) {
+            (PickleValue::Str(module), PickleValue::Str(name)) => {
+              self.stack.push(PickleValue::Global(module, name));
+            }
+            _ => return Err(format_error("bad stack global")),
+          }
+        }
+        // REDUCE, NEWOBJ
+        b'R' | 0x81 => {
+          let args = self.pop()?;
+          let func = self.pop()?;
+          self.stack.push(PickleValue::Reduce(Box::new(func), Box::new(args)));
+        }
+        // BUILD
+        b'b' => {
+          let state = self.pop()?;
+          let obj = self.pop()?;
+          self.stack.push(PickleValue::Build(Box::new(obj), Box::new(state)));
+        }
+        _ => return Err(format_error(&format!("unsupported opcode 0x{:02x} at offset {}", op, self.pos - 1))),
+      }
+    }
+  }
+}
+
+/// Decodes one pickled value from a buffer.
+pub fn unpickle(buf: &[u8]) -> Result<PickleValue, Error> {
+  let mut unpickler = Unpickler{
+    buf:    buf,
+    pos:    0,
+    stack:  vec![],
+    marks:  vec![],
+    memo:   HashMap::new(),
+  };
+  unpickler.run()
+}
+
+pub fn load_pickle_file(path: PathBuf) -> Result<PickleValue, Error> {
+  let mut file = open_file(&path)?;
+  let mut buf = Vec::new();
+  file.read_to_end(&mut buf)?;
+  unpickle(&buf)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// `cPickle.dumps({'data': x, 'labels': [3, 7], 'raw': x.data}, 2)` under
+  /// Python 2, with `x = numpy.arange(6, dtype=numpy.uint8).reshape(2, 3)`
+  /// and `raw` sharing the memoized byte string of `x`.
+  const NDARRAY_DICT: &'static [u8] =
+      b"\x80\x02}q\x00(U\x04dataq\x01cnumpy.core.multiarray\n_reconstruct\nq\x02\
+        cnumpy\nndarray\nq\x03K\x00\x85q\x04U\x01bq\x05\x87q\x06Rq\x07\
+        (K\x01K\x02K\x03\x86q\x08cnumpy\ndtype\nq\x09U\x02u1q\x0aK\x00K\x01\x87q\x0bRq\x0c\
+        (K\x03U\x01|q\x0dNNNJ\xff\xff\xff\xffJ\xff\xff\xff\xffK\x00tq\x0eb\
+        \x89U\x06\x00\x01\x02\x03\x04\x05q\x0ftq\x10b\
+        U\x06labelsq\x11]q\x12(K\x03K\x07e\
+        U\x03rawq\x13h\x0fu.";
+
+  #[test]
+  fn test_unpickle_ndarray_dict() {
+    let value = unpickle(NDARRAY_DICT).unwrap();
+    let (shape, dtype, data) = value.get("data").and_then(|v| v.as_ndarray()).unwrap();
+    assert_eq!(shape, vec![2, 3]);
+    assert_eq!(dtype, "u1");
+    assert_eq!(&data[ .. ], &[0, 1, 2, 3, 4, 5]);
+    let labels: Vec<i64> = value.get("labels").and_then(|v| v.as_list()).unwrap()
+        .iter().map(|x| x.as_i64().unwrap()).collect();
+    assert_eq!(labels, vec![3, 7]);
+    // The memo hands out the same byte buffer instead of a copy.
+    let raw = value.get("raw").and_then(|v| v.as_bytes()).unwrap();
+    assert!(Rc::ptr_eq(&raw, &data));
+    assert!(value.get("missing").is_none());
+  }
+
+  #[test]
+  fn test_unpickle_truncated() {
+    assert!(unpickle(&NDARRAY_DICT[ .. NDARRAY_DICT.len() - 2]).is_err());
+  }
+}
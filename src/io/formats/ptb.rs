@@ -17,18 +17,217 @@ limitations under the License.
 use io::*;
 
 use densearray::prelude::*;
+use sharedmem::{SharedMem};
 
+use std::collections::{HashMap};
+use std::fs::{File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{PathBuf};
+use std::sync::{Arc};
+
+pub const UNK_TOKEN: &'static str = "<unk>";
+pub const EOS_TOKEN: &'static str = "<eos>";
+
+/// Word vocabulary shared by the train, valid and test splits. Ids 0 and 1
+/// are always `<unk>` and `<eos>`.
+#[derive(Clone)]
+pub struct PtbVocab {
+  word_to_id:   HashMap<String, u32>,
+  id_to_word:   Vec<String>,
+}
+
+impl PtbVocab {
+  fn _new() -> Self {
+    let mut vocab = PtbVocab{
+      word_to_id:   HashMap::new(),
+      id_to_word:   vec![],
+    };
+    vocab._insert(UNK_TOKEN);
+    vocab._insert(EOS_TOKEN);
+    vocab
+  }
+
+  fn _insert(&mut self, word: &str) -> u32 {
+    if let Some(&id) = self.word_to_id.get(word) {
+      return id;
+    }
+    let id = self.id_to_word.len() as u32;
+    self.word_to_id.insert(word.to_owned(), id);
+    self.id_to_word.push(word.to_owned());
+    id
+  }
+
+  /// Builds the vocabulary from a training corpus, assigning ids in order of
+  /// first occurrence.
+  pub fn build(train_path: PathBuf) -> Self {
+    match Self::try_build(train_path) {
+      Ok(vocab) => vocab,
+      Err(e) => panic!("failed to build ptb vocab: {}", e),
+    }
+  }
+
+  pub fn try_build(train_path: PathBuf) -> Result<Self, Error> {
+    let file = open_file(&train_path)?;
+    let reader = BufReader::new(file);
+    let mut vocab = Self::_new();
+    for line in reader.lines() {
+      let line = line?;
+      for word in line.split_whitespace() {
+        vocab._insert(word);
+      }
+    }
+    Ok(vocab)
+  }
+
+  /// Loads a vocabulary written by `save`, one word per line in id order.
+  pub fn open(path: PathBuf) -> Self {
+    match Self::try_open(path) {
+      Ok(vocab) => vocab,
+      Err(e) => panic!("failed to load ptb vocab: {}", e),
+    }
+  }
+
+  pub fn try_open(path: PathBuf) -> Result<Self, Error> {
+    let file = open_file(&path)?;
+    let reader = BufReader::new(file);
+    let mut vocab = PtbVocab{
+      word_to_id:   HashMap::new(),
+      id_to_word:   vec![],
+    };
+    for line in reader.lines() {
+      let line = line?;
+      let word = line.trim();
+      if word.is_empty() {
+        continue;
+      }
+      if vocab.word_to_id.contains_key(word) {
+        return Err(Error::Format(format!("ptb vocab: duplicate word: '{}'", word)));
+      }
+      vocab._insert(word);
+    }
+    if vocab.id_to_word.len() < 2 || vocab.id_to_word[0] != UNK_TOKEN || vocab.id_to_word[1] != EOS_TOKEN {
+      return Err(Error::Format(format!("ptb vocab: expected '{}' and '{}' as the first two words", UNK_TOKEN, EOS_TOKEN)));
+    }
+    Ok(vocab)
+  }
+
+  pub fn save(&self, path: PathBuf) -> Result<(), Error> {
+    let mut file = match File::create(&path) {
+      Ok(file) => file,
+      Err(e) => return Err(Error::Open(path, e)),
+    };
+    for word in self.id_to_word.iter() {
+      writeln!(file, "{}", word)?;
+    }
+    Ok(())
+  }
+
+  pub fn len(&self) -> usize {
+    self.id_to_word.len()
+  }
+
+  pub fn unk_id(&self) -> u32 {
+    0
+  }
+
+  pub fn eos_id(&self) -> u32 {
+    1
+  }
+
+  /// Looks up a word, mapping out-of-vocabulary words to `<unk>`.
+  pub fn id(&self, word: &str) -> u32 {
+    match self.word_to_id.get(word) {
+      None => self.unk_id(),
+      Some(&id) => id,
+    }
+  }
+
+  pub fn word(&self, id: u32) -> Option<&str> {
+    self.id_to_word.get(id as usize).map(|w| w.as_str())
+  }
+}
+
+/// A tokenized Penn Treebank split (`ptb.{train,valid,test}.txt` from
+/// Mikolov's RNNLM distribution) as one flat token stream, with `<eos>`
+/// appended to every line.
+///
+/// Item `i` is the BPTT window starting at token `i * stride`: the input is
+/// `seq_len` tokens and the target is the same window shifted by one.
+#[derive(Clone)]
 pub struct MikolovPtbTokenData {
+  seq_len:  usize,
+  stride:   usize,
+  vocab:    Arc<PtbVocab>,
+  tokens:   SharedMem<u32>,
+}
+
+impl MikolovPtbTokenData {
+  pub fn open(path: PathBuf, vocab: Arc<PtbVocab>, seq_len: usize, stride: usize) -> Self {
+    match Self::try_open(path, vocab, seq_len, stride) {
+      Ok(data) => data,
+      Err(e) => panic!("failed to open ptb data: {}", e),
+    }
+  }
+
+  pub fn try_open(path: PathBuf, vocab: Arc<PtbVocab>, seq_len: usize, stride: usize) -> Result<Self, Error> {
+    if seq_len == 0 || stride == 0 {
+      return Err(Error::Format(format!("ptb data: seq_len ({}) and stride ({}) must be positive", seq_len, stride)));
+    }
+    let file = open_file(&path)?;
+    let reader = BufReader::new(file);
+    let mut tokens = vec![];
+    for line in reader.lines() {
+      let line = line?;
+      for word in line.split_whitespace() {
+        tokens.push(vocab.id(word));
+      }
+      tokens.push(vocab.eos_id());
+    }
+    Ok(MikolovPtbTokenData{
+      seq_len:  seq_len,
+      stride:   stride,
+      vocab:    vocab,
+      tokens:   SharedMem::new(tokens),
+    })
+  }
+
+  /// Opens the train, valid and test splits in `dir`, building the
+  /// vocabulary from the train split.
+  pub fn open_splits(dir: PathBuf, seq_len: usize, stride: usize) -> (Self, Self, Self) {
+    let vocab = Arc::new(PtbVocab::build(dir.join("ptb.train.txt")));
+    let train = Self::open(dir.join("ptb.train.txt"), vocab.clone(), seq_len, stride);
+    let valid = Self::open(dir.join("ptb.valid.txt"), vocab.clone(), seq_len, stride);
+    let test = Self::open(dir.join("ptb.test.txt"), vocab, seq_len, stride);
+    (train, valid, test)
+  }
+
+  pub fn vocab(&self) -> &Arc<PtbVocab> {
+    &self.vocab
+  }
+
+  pub fn num_tokens(&self) -> usize {
+    self.tokens.len()
+  }
 }
 
 impl IndexedData for MikolovPtbTokenData {
-  type Item = Vec<Vec<u8>>;
+  type Item = (Array1d<u32, SharedMem<u32>>, Array1d<u32, SharedMem<u32>>);
 
   fn len(&self) -> usize {
-    unimplemented!();
+    let num_tokens = self.tokens.len();
+    if num_tokens < self.seq_len + 1 {
+      return 0;
+    }
+    (num_tokens - self.seq_len - 1) / self.stride + 1
   }
 
   fn get(&mut self, idx: usize) -> Self::Item {
-    unimplemented!();
+    assert!(idx < self.len());
+    let start = idx * self.stride;
+    let input_buf = self.tokens.slice_v2(start .. start + self.seq_len);
+    let target_buf = self.tokens.slice_v2(start + 1 .. start + self.seq_len + 1);
+    (Array1d::from_storage(self.seq_len, input_buf), Array1d::from_storage(self.seq_len, target_buf))
   }
 }
+
+impl_stateless!(MikolovPtbTokenData);
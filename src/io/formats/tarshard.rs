@@ -0,0 +1,169 @@
+/*
+Copyright 2017 the superlearn authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Datasets packed as a list of tar shards in which the members of one
+//! sample share a key and differ by extension, e.g. `000123.jpg`,
+//! `000123.cls` and `000123.json`.
+
+use io::*;
+
+use extar::{TarBufferExt, TarBuffer};
+use sharedmem::{MemoryMap, SharedMem};
+
+use std::collections::{HashMap};
+use std::io::{Cursor};
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy)]
+struct Member {
+  offset:   usize,
+  length:   usize,
+}
+
+#[derive(Clone)]
+struct Sample {
+  shard:    usize,
+  key:      String,
+  fields:   Vec<(String, Member)>,
+}
+
+fn mmap_shard(shard_path: &Path) -> Result<SharedMem<u8>, Error> {
+  let shard_file = open_file(shard_path)?;
+  let file_sz = shard_file.metadata()?.len() as usize;
+  match MemoryMap::open_with_offset(shard_file, 0, file_sz) {
+    Ok(buf) => Ok(SharedMem::new(buf)),
+    Err(e) => Err(Error::Mmap(format!("'{}': {:?}", shard_path.display(), e))),
+  }
+}
+
+/// Splits a member path into its key and extension at the first '.' of the
+/// file name, so that `a/b/000123.seg.png` has key `a/b/000123` and extension
+/// `seg.png`. Members without an extension (e.g. directories) are skipped.
+fn split_key(path: &str) -> Option<(&str, &str)> {
+  let name_start = path.rfind('/').map_or(0, |p| p + 1);
+  match path[name_start .. ].find('.') {
+    None => None,
+    Some(0) => None,
+    Some(dot) => {
+      let dot = name_start + dot;
+      if dot + 1 == path.len() {
+        None
+      } else {
+        Some((&path[ .. dot], &path[dot + 1 .. ]))
+      }
+    }
+  }
+}
+
+#[derive(Clone)]
+pub struct TarShardData {
+  shard_paths:  Vec<PathBuf>,
+  shards:       Vec<SharedMem<u8>>,
+  samples:      Vec<Sample>,
+}
+
+impl TarShardData {
+  pub fn open(shard_paths: Vec<PathBuf>) -> TarShardData {
+    match Self::try_open(shard_paths) {
+      Ok(data) => data,
+      Err(e) => panic!("failed to open tar shard data: {}", e),
+    }
+  }
+
+  /// Indexes the shards in order. Consecutive members sharing a key form one
+  /// sample; a key that reappears later in a shard starts a new sample.
+  pub fn try_open(shard_paths: Vec<PathBuf>) -> Result<TarShardData, Error> {
+    let mut shards = Vec::with_capacity(shard_paths.len());
+    let mut samples: Vec<Sample> = vec![];
+    for (shard_idx, shard_path) in shard_paths.iter().enumerate() {
+      let shard_buf = mmap_shard(shard_path)?;
+      let mut archive = TarBuffer::new(Cursor::new(shard_buf.clone()));
+      let entries = archive.raw_entries().map_err(|e| Error::Format(format!("tar: '{}': {:?}", shard_path.display(), e)))?;
+      for (member_idx, entry) in entries.enumerate() {
+        let entry = entry.map_err(|e| Error::Format(format!("tar: '{}': {:?}", shard_path.display(), e)))?;
+        let pos = entry.raw_file_position();
+        let size = entry.file_size();
+        if pos + size > shard_buf.len() as u64 {
+          return Err(Error::Format(format!("tar shard '{}': member {} overruns the archive", shard_path.display(), member_idx)));
+        }
+        let path = match entry.path.to_str() {
+          None => return Err(Error::Format(format!("tar shard '{}': non-utf8 member path: {:?}", shard_path.display(), entry.path))),
+          Some(path) => path.to_owned(),
+        };
+        let (key, ext) = match split_key(&path) {
+          None => continue,
+          Some((key, ext)) => (key.to_owned(), ext.to_owned()),
+        };
+        let member = Member{offset: pos as usize, length: size as usize};
+        let same_sample = match samples.last() {
+          Some(last) => last.shard == shard_idx && last.key == key,
+          None => false,
+        };
+        if same_sample {
+          let last = samples.last_mut().unwrap();
+          if last.fields.iter().any(|&(ref e, _)| *e == ext) {
+            return Err(Error::Format(format!("tar shard '{}': duplicate member '{}'", shard_path.display(), path)));
+          }
+          last.fields.push((ext, member));
+        } else {
+          samples.push(Sample{
+            shard:    shard_idx,
+            key:      key,
+            fields:   vec![(ext, member)],
+          });
+        }
+      }
+      shards.push(shard_buf);
+    }
+    Ok(TarShardData{
+      shard_paths:  shard_paths,
+      shards:       shards,
+      samples:      samples,
+    })
+  }
+
+  pub fn num_shards(&self) -> usize {
+    self.shards.len()
+  }
+
+  pub fn shard_path(&self, idx: usize) -> &Path {
+    &self.shard_paths[self.samples[idx].shard]
+  }
+
+  pub fn key(&self, idx: usize) -> &str {
+    &self.samples[idx].key
+  }
+}
+
+impl IndexedData for TarShardData {
+  type Item = HashMap<String, SharedMem<u8>>;
+
+  fn len(&self) -> usize {
+    self.samples.len()
+  }
+
+  fn get(&mut self, idx: usize) -> Self::Item {
+    let sample = &self.samples[idx];
+    let shard_buf = &self.shards[sample.shard];
+    let mut fields = HashMap::with_capacity(sample.fields.len());
+    for &(ref ext, member) in sample.fields.iter() {
+      fields.insert(ext.clone(), shard_buf.slice_v2(member.offset .. member.offset + member.length));
+    }
+    fields
+  }
+}
+
+impl_stateless!(TarShardData);
@@ -27,11 +27,21 @@ pub struct SharedVarrayData {
 
 impl SharedVarrayData {
   pub fn open(prefix: PathBuf) -> SharedVarrayData {
-    let db = SharedVarrayDb::open(&prefix).unwrap();
-    SharedVarrayData{
-      db:   db,
+    match Self::try_open(prefix) {
+      Ok(data) => data,
+      Err(e) => panic!("failed to open varraydb: {}", e),
     }
   }
+
+  pub fn try_open(prefix: PathBuf) -> Result<SharedVarrayData, Error> {
+    let db = match SharedVarrayDb::open(&prefix) {
+      Ok(db) => db,
+      Err(e) => return Err(Error::Format(format!("varraydb '{}': {:?}", prefix.display(), e))),
+    };
+    Ok(SharedVarrayData{
+      db:   db,
+    })
+  }
 }
 
 impl IndexedData for SharedVarrayData {
@@ -46,3 +56,5 @@ impl IndexedData for SharedVarrayData {
     item
   }
 }
+
+impl_stateless!(SharedVarrayData);
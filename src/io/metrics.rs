@@ -0,0 +1,305 @@
+/*
+Copyright 2017 the superlearn authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use io::{SizeOf};
+use io::state::{Stateful};
+use io::transforms::{Transform};
+use util::{Stopwatch, instant_diff_seconds};
+
+use rand::{Rng, thread_rng};
+use rustc_serialize::json;
+use std::cmp::{min};
+use std::io::{Write, Result as IoResult};
+use std::mem::{replace};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Instant};
+
+/// The most latencies kept per stage between reports; beyond this the
+/// samples are a uniform reservoir over all calls.
+const MAX_LATENCY_SAMPLES: usize = 4096;
+
+#[derive(Default)]
+struct StageCounters {
+  items:        usize,
+  bytes:        usize,
+  latencies:    Vec<f64>,
+}
+
+/// Shared timing counters for one pipeline stage. Clones refer to the same
+/// counters, so one `StageMetrics` can be handed to every worker's copy of a
+/// transform.
+#[derive(Clone)]
+pub struct StageMetrics {
+  name:     String,
+  counters: Arc<Mutex<StageCounters>>,
+}
+
+impl StageMetrics {
+  pub fn new(name: &str) -> Self {
+    StageMetrics{
+      name:     name.to_owned(),
+      counters: Arc::new(Mutex::new(StageCounters::default())),
+    }
+  }
+
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  pub fn record(&self, latency_s: f64, bytes: usize) {
+    let mut counters = self.counters.lock().unwrap();
+    counters.items += 1;
+    counters.bytes += bytes;
+    if counters.latencies.len() < MAX_LATENCY_SAMPLES {
+      counters.latencies.push(latency_s);
+    } else {
+      let j = thread_rng().gen_range(0, counters.items);
+      if j < MAX_LATENCY_SAMPLES {
+        counters.latencies[j] = latency_s;
+      }
+    }
+  }
+
+  fn _drain(&self) -> StageCounters {
+    let mut counters = self.counters.lock().unwrap();
+    replace(&mut *counters, StageCounters::default())
+  }
+}
+
+fn dst_size_of<D>(dst: &D) -> usize where D: SizeOf {
+  dst.size_of()
+}
+
+/// Wraps a transform and records the latency of every call, and with
+/// `new_sized` also its output size.
+pub struct Timed<T> where T: Transform {
+  stage:        StageMetrics,
+  stopwatch:    Stopwatch,
+  size_of:      Option<fn (&T::Dst) -> usize>,
+  inner:        T,
+}
+
+impl<T> Timed<T> where T: Transform {
+  pub fn new(stage: StageMetrics, inner: T) -> Self {
+    Timed{
+      stage:        stage,
+      stopwatch:    Stopwatch::new(),
+      size_of:      None,
+      inner:        inner,
+    }
+  }
+
+  pub fn new_sized(stage: StageMetrics, inner: T) -> Self where T::Dst: SizeOf {
+    Timed{
+      stage:        stage,
+      stopwatch:    Stopwatch::new(),
+      size_of:      Some(dst_size_of::<T::Dst> as fn (&T::Dst) -> usize),
+      inner:        inner,
+    }
+  }
+}
+
+impl<T> Transform for Timed<T> where T: Transform {
+  type Src = T::Src;
+  type Dst = T::Dst;
+
+  fn transform(&mut self, src: T::Src) -> T::Dst {
+    self.stopwatch.lap();
+    let dst = self.inner.transform(src);
+    self.stopwatch.lap();
+    let bytes = match self.size_of {
+      None => 0,
+      Some(size_of) => size_of(&dst),
+    };
+    self.stage.record(self.stopwatch.elapsed(), bytes);
+    dst
+  }
+}
+
+impl<T> Stateful for Timed<T> where T: Transform + Stateful {
+  type State = T::State;
+
+  fn state(&mut self) -> T::State {
+    self.inner.state()
+  }
+
+  fn restore(&mut self, state: &T::State) {
+    self.inner.restore(state);
+  }
+}
+
+/// Counters for a queue between a producer and its consumers. A stall is
+/// counted whenever a producer finds the queue full or a consumer finds it
+/// empty and has to block.
+#[derive(Default)]
+pub struct QueueMetrics {
+  items:                AtomicUsize,
+  producer_stalls:      AtomicUsize,
+  consumer_stalls:      AtomicUsize,
+  occupancy_sum:        AtomicUsize,
+  occupancy_samples:    AtomicUsize,
+}
+
+impl QueueMetrics {
+  pub fn record_item(&self) {
+    self.items.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn record_producer_stall(&self) {
+    self.producer_stalls.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn record_consumer_stall(&self) {
+    self.consumer_stalls.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn record_occupancy(&self, occupancy: usize) {
+    self.occupancy_sum.fetch_add(occupancy, Ordering::Relaxed);
+    self.occupancy_samples.fetch_add(1, Ordering::Relaxed);
+  }
+}
+
+#[derive(Clone, Debug, RustcEncodable)]
+pub struct StageReport {
+  pub name:             String,
+  pub items:            usize,
+  pub items_per_s:      f64,
+  pub bytes_per_s:      f64,
+  pub p50_latency_ms:   f64,
+  pub p99_latency_ms:   f64,
+}
+
+#[derive(Clone, Debug, RustcEncodable)]
+pub struct QueueReport {
+  pub name:             String,
+  pub items:            usize,
+  pub items_per_s:      f64,
+  pub producer_stalls:  usize,
+  pub consumer_stalls:  usize,
+  pub mean_occupancy:   f64,
+}
+
+#[derive(Clone, Debug, RustcEncodable)]
+pub struct MetricsReport {
+  pub elapsed_s:    f64,
+  pub stages:       Vec<StageReport>,
+  pub queues:       Vec<QueueReport>,
+}
+
+impl MetricsReport {
+  pub fn print(&self) {
+    println!("io metrics: elapsed: {:.3} s", self.elapsed_s);
+    for stage in self.stages.iter() {
+      println!("io metrics:   stage: {} items: {} items/s: {:.1} MB/s: {:.2} p50: {:.3} ms p99: {:.3} ms",
+          stage.name, stage.items, stage.items_per_s, stage.bytes_per_s * 1.0e-6,
+          stage.p50_latency_ms, stage.p99_latency_ms);
+    }
+    for queue in self.queues.iter() {
+      println!("io metrics:   queue: {} items: {} items/s: {:.1} producer stalls: {} consumer stalls: {} mean occupancy: {:.1}",
+          queue.name, queue.items, queue.items_per_s,
+          queue.producer_stalls, queue.consumer_stalls, queue.mean_occupancy);
+    }
+  }
+
+  pub fn write_json_line<W>(&self, writer: &mut W) -> IoResult<()> where W: Write {
+    let line = json::encode(self).unwrap();
+    writeln!(writer, "{}", line)
+  }
+}
+
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+  if sorted.is_empty() {
+    return 0.0;
+  }
+  let rank = (p * sorted.len() as f64).ceil() as usize;
+  let idx = if rank == 0 { 0 } else { rank - 1 };
+  sorted[min(idx, sorted.len() - 1)]
+}
+
+/// Collects per-stage and per-queue counters into periodic reports. Each
+/// report covers the interval since the previous one.
+pub struct MetricsReporter {
+  last_time:    Instant,
+  stages:       Vec<StageMetrics>,
+  queues:       Vec<(String, Arc<QueueMetrics>)>,
+}
+
+impl MetricsReporter {
+  pub fn new() -> Self {
+    MetricsReporter{
+      last_time:    Instant::now(),
+      stages:       vec![],
+      queues:       vec![],
+    }
+  }
+
+  pub fn add_stage(&mut self, stage: &StageMetrics) {
+    self.stages.push(stage.clone());
+  }
+
+  pub fn add_queue(&mut self, name: &str, queue: Arc<QueueMetrics>) {
+    self.queues.push((name.to_owned(), queue));
+  }
+
+  /// Returns a report if at least `interval_s` seconds passed since the
+  /// previous one.
+  pub fn maybe_report(&mut self, interval_s: f64) -> Option<MetricsReport> {
+    if instant_diff_seconds(Instant::now(), self.last_time) < interval_s {
+      return None;
+    }
+    Some(self.report())
+  }
+
+  pub fn report(&mut self) -> MetricsReport {
+    let now = Instant::now();
+    let elapsed = instant_diff_seconds(now, self.last_time);
+    self.last_time = now;
+    let rate = |count: usize| if elapsed > 0.0 { count as f64 / elapsed } else { 0.0 };
+    let mut stage_reports = Vec::with_capacity(self.stages.len());
+    for stage in self.stages.iter() {
+      let mut counters = stage._drain();
+      counters.latencies.sort_by(|x, y| x.partial_cmp(y).unwrap());
+      stage_reports.push(StageReport{
+        name:             stage.name.clone(),
+        items:            counters.items,
+        items_per_s:      rate(counters.items),
+        bytes_per_s:      rate(counters.bytes),
+        p50_latency_ms:   1.0e3 * percentile(&counters.latencies, 0.50),
+        p99_latency_ms:   1.0e3 * percentile(&counters.latencies, 0.99),
+      });
+    }
+    let mut queue_reports = Vec::with_capacity(self.queues.len());
+    for &(ref name, ref queue) in self.queues.iter() {
+      let items = queue.items.swap(0, Ordering::Relaxed);
+      let occupancy_sum = queue.occupancy_sum.swap(0, Ordering::Relaxed);
+      let occupancy_samples = queue.occupancy_samples.swap(0, Ordering::Relaxed);
+      queue_reports.push(QueueReport{
+        name:             name.clone(),
+        items:            items,
+        items_per_s:      rate(items),
+        producer_stalls:  queue.producer_stalls.swap(0, Ordering::Relaxed),
+        consumer_stalls:  queue.consumer_stalls.swap(0, Ordering::Relaxed),
+        mean_occupancy:   if occupancy_samples > 0 { occupancy_sum as f64 / occupancy_samples as f64 } else { 0.0 },
+      });
+    }
+    MetricsReport{
+      elapsed_s:    elapsed,
+      stages:       stage_reports,
+      queues:       queue_reports,
+    }
+  }
+}
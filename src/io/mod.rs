@@ -14,24 +14,244 @@ See the License for the specific language governing permissions and
 limitations under the License.
 */
 
+use self::metrics::{QueueMetrics};
+use self::state::{Stateful, checkpoint_rng, restore_rng};
+use self::transforms::{Transform};
+
+use densearray::prelude::*;
 use rng::xorshift::*;
+use sharedmem::{SharedMem};
 
 use rand::{Rng, SeedableRng};
 use rand::chacha::{ChaChaRng};
 use std::cmp::{min};
-use std::collections::{VecDeque};
-use std::sync::mpsc::{SyncSender, Receiver, sync_channel};
-use std::thread::{JoinHandle, spawn};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::any::{Any};
+use std::error::{Error as StdError};
+use std::fmt;
+use std::fs::{File};
+use std::io::{Error as IoError};
+use std::mem::{size_of};
+use std::ops::{Deref};
+use std::path::{Path, PathBuf};
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{SyncSender, Receiver, TryRecvError, TrySendError, sync_channel};
+use std::thread::{Builder as ThreadBuilder, JoinHandle, spawn};
+
+#[macro_use]
+pub mod state;
 
+pub mod collate;
 pub mod formats;
+pub mod metrics;
+#[cfg(unix)]
+pub mod multiproc;
+pub mod parallel;
+pub mod prefetch;
 pub mod transforms;
 
+#[derive(Debug)]
+pub enum Error {
+  Io(IoError),
+  Open(PathBuf, IoError),
+  Mmap(String),
+  Format(String),
+  UnknownWnid(String),
+  OutOfRange(usize, usize),
+  Corrupt(usize, String),
+}
+
+impl From<IoError> for Error {
+  fn from(e: IoError) -> Error {
+    Error::Io(e)
+  }
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      Error::Io(ref e) => write!(f, "io error: {}", e),
+      Error::Open(ref path, ref e) => write!(f, "failed to open '{}': {}", path.display(), e),
+      Error::Mmap(ref msg) => write!(f, "failed to mmap: {}", msg),
+      Error::Format(ref msg) => write!(f, "bad format: {}", msg),
+      Error::UnknownWnid(ref wnid) => write!(f, "unknown wordnet id: '{}'", wnid),
+      Error::OutOfRange(idx, len) => write!(f, "index {} out of range for length {}", idx, len),
+      Error::Corrupt(idx, ref msg) => write!(f, "corrupt record at index {}: {}", idx, msg),
+    }
+  }
+}
+
+impl StdError for Error {
+  fn source(&self) -> Option<&(dyn StdError + 'static)> {
+    match *self {
+      Error::Io(ref e) => Some(e),
+      Error::Open(_, ref e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+  if let Some(msg) = payload.downcast_ref::<&'static str>() {
+    (*msg).to_owned()
+  } else if let Some(msg) = payload.downcast_ref::<String>() {
+    msg.clone()
+  } else {
+    "<non-string panic payload>".to_owned()
+  }
+}
+
+fn open_file(path: &Path) -> Result<File, Error> {
+  File::open(path).map_err(|e| Error::Open(path.to_owned(), e))
+}
+
+/// The number of bytes of item data, used to budget caches and queues.
+pub trait SizeOf {
+  fn size_of(&self) -> usize;
+}
+
+impl SizeOf for u8 {
+  fn size_of(&self) -> usize {
+    size_of::<u8>()
+  }
+}
+
+impl SizeOf for u32 {
+  fn size_of(&self) -> usize {
+    size_of::<u32>()
+  }
+}
+
+impl SizeOf for usize {
+  fn size_of(&self) -> usize {
+    size_of::<usize>()
+  }
+}
+
+impl SizeOf for f32 {
+  fn size_of(&self) -> usize {
+    size_of::<f32>()
+  }
+}
+
+impl<T> SizeOf for Vec<T> {
+  fn size_of(&self) -> usize {
+    self.len() * size_of::<T>()
+  }
+}
+
+impl<T> SizeOf for SharedMem<T> {
+  fn size_of(&self) -> usize {
+    self.len() * size_of::<T>()
+  }
+}
+
+impl<T, S> SizeOf for Array1d<T, S> where S: Deref<Target=[T]> {
+  fn size_of(&self) -> usize {
+    self.dim().flat_len() * size_of::<T>()
+  }
+}
+
+impl<T, S> SizeOf for Array2d<T, S> where S: Deref<Target=[T]> {
+  fn size_of(&self) -> usize {
+    self.dim().flat_len() * size_of::<T>()
+  }
+}
+
+impl<T, S> SizeOf for Array3d<T, S> where S: Deref<Target=[T]> {
+  fn size_of(&self) -> usize {
+    self.dim().flat_len() * size_of::<T>()
+  }
+}
+
+impl<K, V> SizeOf for HashMap<K, V> where V: SizeOf {
+  fn size_of(&self) -> usize {
+    self.values().fold(0, |sz, v| sz + v.size_of())
+  }
+}
+
+impl<T, E> SizeOf for Result<T, E> where T: SizeOf {
+  fn size_of(&self) -> usize {
+    match *self {
+      Ok(ref x) => x.size_of(),
+      Err(_) => 0,
+    }
+  }
+}
+
+impl<A, B> SizeOf for (A, B) where A: SizeOf, B: SizeOf {
+  fn size_of(&self) -> usize {
+    self.0.size_of() + self.1.size_of()
+  }
+}
+
+impl<A, B, C> SizeOf for (A, B, C) where A: SizeOf, B: SizeOf, C: SizeOf {
+  fn size_of(&self) -> usize {
+    self.0.size_of() + self.1.size_of() + self.2.size_of()
+  }
+}
+
+/// Fisher-Yates shuffle.
+pub fn shuffle_indices<R>(perm: &mut [usize], rng: &mut R) where R: Rng {
+  for i in (1 .. perm.len()).rev() {
+    let j = rng.gen_range(0, i + 1);
+    perm.swap(i, j);
+  }
+}
+
+fn split_by_fractions(idxs: &[usize], fractions: &[f64]) -> Vec<Vec<usize>> {
+  assert!(!fractions.is_empty());
+  let mut total = 0.0;
+  for &frac in fractions.iter() {
+    assert!(frac >= 0.0);
+    total += frac;
+  }
+  assert!(total > 0.0);
+  let len = idxs.len();
+  let mut parts = Vec::with_capacity(fractions.len());
+  let mut cumulative = 0.0;
+  let mut lower = 0;
+  for (p, &frac) in fractions.iter().enumerate() {
+    cumulative += frac;
+    let upper = if p + 1 == fractions.len() {
+      len
+    } else {
+      min(len, (cumulative / total * len as f64).round() as usize)
+    };
+    let upper = if upper < lower { lower } else { upper };
+    parts.push(idxs[lower .. upper].to_owned());
+    lower = upper;
+  }
+  parts
+}
+
+fn select_parts<Inner>(inner: Inner, parts: Vec<Vec<usize>>) -> Vec<SelectData<Inner>> where Inner: IndexedData + Clone {
+  let mut datas = Vec::with_capacity(parts.len());
+  for mut part in parts {
+    // Keep each part in source order, which is friendlier to mmaps.
+    part.sort();
+    datas.push(inner.clone().select(part));
+  }
+  datas
+}
+
 pub trait IndexedData {
   type Item;
 
   fn len(&self) -> usize;
   fn get(&mut self, idx: usize) -> Self::Item;
 
+  /// Like `get`, but reports an out-of-range index or a corrupt record
+  /// instead of panicking, so that the caller may skip it.
+  fn try_get(&mut self, idx: usize) -> Result<Self::Item, Error> {
+    if idx >= self.len() {
+      return Err(Error::OutOfRange(idx, self.len()));
+    }
+    Ok(self.get(idx))
+  }
+
   fn range(self, lower: usize, upper: usize) -> RangeData<Self> where Self: Sized {
     assert!(upper <= self.len());
     assert!(lower <= upper);
@@ -56,6 +276,113 @@ pub trait IndexedData {
     }
   }
 
+  fn select(self, indices: Vec<usize>) -> SelectData<Self> where Self: Sized {
+    let len = self.len();
+    for &idx in indices.iter() {
+      assert!(idx < len);
+    }
+    SelectData{
+      indices:  indices,
+      inner:    self,
+    }
+  }
+
+  /// Randomly splits the source into disjoint parts with sizes proportional
+  /// to `fractions`.
+  fn random_split(self, fractions: &[f64], seed_rng: &mut ChaChaRng) -> Vec<SelectData<Self>> where Self: Sized + Clone {
+    let mut rng = Xorshiftplus128Rng::from_seed([seed_rng.next_u64(), seed_rng.next_u64()]);
+    let mut perm: Vec<usize> = (0 .. self.len()).collect();
+    shuffle_indices(&mut perm, &mut rng);
+    let parts = split_by_fractions(&perm, fractions);
+    select_parts(self, parts)
+  }
+
+  /// Like `random_split`, but splits every class separately so that each
+  /// part has (up to rounding) the same label distribution as the source.
+  /// Labels are read with `get`; see `class_balanced_sample`.
+  fn stratified_split<A>(mut self, fractions: &[f64], seed_rng: &mut ChaChaRng) -> Vec<SelectData<Self>> where Self: Sized + Clone + IndexedData<Item=(A, u32)> {
+    let mut labels = Vec::with_capacity(self.len());
+    for idx in 0 .. self.len() {
+      let (_, label) = self.get(idx);
+      labels.push(label);
+    }
+    self.stratified_split_with_labels(&labels, fractions, seed_rng)
+  }
+
+  /// Like `stratified_split`, with the label of every item given by `labels`.
+  fn stratified_split_with_labels(self, labels: &[u32], fractions: &[f64], seed_rng: &mut ChaChaRng) -> Vec<SelectData<Self>> where Self: Sized + Clone {
+    assert_eq!(self.len(), labels.len());
+    let mut rng = Xorshiftplus128Rng::from_seed([seed_rng.next_u64(), seed_rng.next_u64()]);
+    let mut classes: Vec<Vec<usize>> = vec![];
+    for (idx, &label) in labels.iter().enumerate() {
+      let label = label as usize;
+      if label >= classes.len() {
+        classes.resize(label + 1, vec![]);
+      }
+      classes[label].push(idx);
+    }
+    let mut parts: Vec<Vec<usize>> = vec![vec![]; fractions.len()];
+    for class_idxs in classes.iter_mut() {
+      shuffle_indices(class_idxs, &mut rng);
+      let class_parts = split_by_fractions(class_idxs, fractions);
+      for (part, class_part) in parts.iter_mut().zip(class_parts.into_iter()) {
+        part.extend(class_part);
+      }
+    }
+    select_parts(self, parts)
+  }
+
+  /// Returns the `(train, holdout)` views for fold `fold` of a randomly
+  /// shuffled `k`-fold partition. Use identically seeded `seed_rng`s to get
+  /// consistent folds across calls.
+  fn kfold(self, k: usize, fold: usize, seed_rng: &mut ChaChaRng) -> (SelectData<Self>, SelectData<Self>) where Self: Sized + Clone {
+    assert!(k >= 2);
+    assert!(fold < k);
+    let mut rng = Xorshiftplus128Rng::from_seed([seed_rng.next_u64(), seed_rng.next_u64()]);
+    let len = self.len();
+    let mut perm: Vec<usize> = (0 .. len).collect();
+    shuffle_indices(&mut perm, &mut rng);
+    let lower = fold * len / k;
+    let upper = (fold + 1) * len / k;
+    let mut train_idxs = Vec::with_capacity(len - (upper - lower));
+    train_idxs.extend_from_slice(&perm[ .. lower]);
+    train_idxs.extend_from_slice(&perm[upper .. ]);
+    let mut holdout_idxs = perm[lower .. upper].to_owned();
+    train_idxs.sort();
+    holdout_idxs.sort();
+    (self.clone().select(train_idxs), self.select(holdout_idxs))
+  }
+
+  fn map<T>(self, transform: T) -> MapData<Self, T> where Self: Sized, T: Transform<Src=Self::Item> {
+    MapData{
+      transform:    transform,
+      inner:        self,
+    }
+  }
+
+  /// Memoizes `get` in an LRU cache holding at most `max_bytes` of items.
+  /// Note that the outputs of random transforms are frozen once cached.
+  fn cache(self, max_bytes: usize) -> CacheData<Self> where Self: Sized, Self::Item: Clone + SizeOf {
+    CacheData{
+      max_bytes:    max_bytes,
+      cur_bytes:    0,
+      clock:        0,
+      entries:      HashMap::new(),
+      lru:          BTreeMap::new(),
+      inner:        self,
+    }
+  }
+
+  /// Eagerly pulls every item into memory.
+  fn materialize(mut self) -> VecData<Self::Item> where Self: Sized {
+    let len = self.len();
+    let mut items = Vec::with_capacity(len);
+    for idx in 0 .. len {
+      items.push(self.get(idx));
+    }
+    VecData::new(items)
+  }
+
   fn cycle(self) -> CycleData<Self> where Self: Sized {
     CycleData{
       counter:  0,
@@ -69,6 +396,58 @@ pub trait IndexedData {
       inner:    self,
     }
   }
+
+  fn weighted_sample(self, weights: Vec<f64>, seed_rng: &mut ChaChaRng) -> WeightedSampleData<Self> where Self: Sized {
+    assert_eq!(self.len(), weights.len());
+    WeightedSampleData{
+      table:    AliasTable::new(&weights),
+      rng:      Xorshiftplus128Rng::from_seed([seed_rng.next_u64(), seed_rng.next_u64()]),
+      inner:    self,
+    }
+  }
+
+  /// Samples labeled items so that every class is drawn equally often. The
+  /// labels are found by scanning the whole source once with `get`, so call
+  /// this on the undecoded source (before e.g. `map(JpegDecoder)`). An empty
+  /// source yields an empty sampler.
+  fn class_balanced_sample<A>(mut self, seed_rng: &mut ChaChaRng) -> WeightedSampleData<Self> where Self: Sized + IndexedData<Item=(A, u32)> {
+    let mut labels = Vec::with_capacity(self.len());
+    for idx in 0 .. self.len() {
+      let (_, label) = self.get(idx);
+      labels.push(label);
+    }
+    self.class_balanced_sample_with_labels(&labels, seed_rng)
+  }
+
+  /// Like `class_balanced_sample`, with the label of every item given by
+  /// `labels` instead of being read from the source.
+  fn class_balanced_sample_with_labels(self, labels: &[u32], seed_rng: &mut ChaChaRng) -> WeightedSampleData<Self> where Self: Sized {
+    assert_eq!(self.len(), labels.len());
+    let mut counts: Vec<usize> = vec![];
+    for &label in labels.iter() {
+      let label = label as usize;
+      if label >= counts.len() {
+        counts.resize(label + 1, 0);
+      }
+      counts[label] += 1;
+    }
+    let mut weights = Vec::with_capacity(labels.len());
+    for &label in labels.iter() {
+      weights.push(1.0 / counts[label as usize] as f64);
+    }
+    self.weighted_sample(weights, seed_rng)
+  }
+
+  fn shuffle_epochs(self, seed_rng: &mut ChaChaRng) -> ShuffleEpochsData<Self> where Self: Sized {
+    ShuffleEpochsData{
+      epoch:        0,
+      counter:      0,
+      perm:         Vec::with_capacity(self.len()),
+      perm_seed:    (0, 0),
+      rng:          Xorshiftplus128Rng::from_seed([seed_rng.next_u64(), seed_rng.next_u64()]),
+      inner:        self,
+    }
+  }
 }
 
 #[derive(Clone)]
@@ -89,6 +468,347 @@ impl<Inner> IndexedData for RangeData<Inner> where Inner: IndexedData {
     assert!(idx < self.len());
     self.inner.get(self.lower + idx)
   }
+
+  fn try_get(&mut self, idx: usize) -> Result<Self::Item, Error> {
+    if idx >= self.len() {
+      return Err(Error::OutOfRange(idx, self.len()));
+    }
+    self.inner.try_get(self.lower + idx)
+  }
+}
+
+impl<Inner> Stateful for RangeData<Inner> where Inner: IndexedData + Stateful {
+  type State = Inner::State;
+
+  fn state(&mut self) -> Self::State {
+    self.inner.state()
+  }
+
+  fn restore(&mut self, state: &Self::State) {
+    self.inner.restore(state);
+  }
+}
+
+#[derive(Clone)]
+pub struct SelectData<Inner> where Inner: IndexedData {
+  indices:  Vec<usize>,
+  inner:    Inner,
+}
+
+impl<Inner> SelectData<Inner> where Inner: IndexedData {
+  pub fn indices(&self) -> &[usize] {
+    &self.indices
+  }
+}
+
+impl<Inner> IndexedData for SelectData<Inner> where Inner: IndexedData {
+  type Item = Inner::Item;
+
+  fn len(&self) -> usize {
+    self.indices.len()
+  }
+
+  fn get(&mut self, idx: usize) -> Self::Item {
+    let inner_idx = self.indices[idx];
+    self.inner.get(inner_idx)
+  }
+
+  fn try_get(&mut self, idx: usize) -> Result<Self::Item, Error> {
+    if idx >= self.len() {
+      return Err(Error::OutOfRange(idx, self.len()));
+    }
+    let inner_idx = self.indices[idx];
+    self.inner.try_get(inner_idx)
+  }
+}
+
+impl<Inner> Stateful for SelectData<Inner> where Inner: IndexedData + Stateful {
+  type State = Inner::State;
+
+  fn state(&mut self) -> Self::State {
+    self.inner.state()
+  }
+
+  fn restore(&mut self, state: &Self::State) {
+    self.inner.restore(state);
+  }
+}
+
+#[derive(Clone)]
+pub struct VecData<Item> {
+  items:    Vec<Item>,
+}
+
+impl<Item> VecData<Item> {
+  pub fn new(items: Vec<Item>) -> Self {
+    VecData{items: items}
+  }
+
+  pub fn into_inner(self) -> Vec<Item> {
+    self.items
+  }
+}
+
+impl<Item> IndexedData for VecData<Item> where Item: Clone {
+  type Item = Item;
+
+  fn len(&self) -> usize {
+    self.items.len()
+  }
+
+  fn get(&mut self, idx: usize) -> Item {
+    self.items[idx].clone()
+  }
+}
+
+impl_stateless!(VecData<Item>);
+
+pub struct CacheData<Inner> where Inner: IndexedData {
+  max_bytes:    usize,
+  cur_bytes:    usize,
+  clock:        u64,
+  // Maps an index to its (last use, size in bytes, item).
+  entries:      HashMap<usize, (u64, usize, Inner::Item)>,
+  // Maps a last use to its index; the first entry is the LRU item.
+  lru:          BTreeMap<u64, usize>,
+  inner:        Inner,
+}
+
+impl<Inner> CacheData<Inner> where Inner: IndexedData, Inner::Item: Clone + SizeOf {
+  pub fn cached_bytes(&self) -> usize {
+    self.cur_bytes
+  }
+
+  fn _lookup(&mut self, idx: usize) -> Option<Inner::Item> {
+    if !self.entries.contains_key(&idx) {
+      return None;
+    }
+    self.clock += 1;
+    let entry = self.entries.get_mut(&idx).unwrap();
+    self.lru.remove(&entry.0);
+    entry.0 = self.clock;
+    self.lru.insert(self.clock, idx);
+    Some(entry.2.clone())
+  }
+
+  fn _insert(&mut self, idx: usize, item: Inner::Item) {
+    let item_bytes = item.size_of();
+    if item_bytes > self.max_bytes {
+      return;
+    }
+    while self.cur_bytes + item_bytes > self.max_bytes {
+      let (lru_clock, lru_idx) = match self.lru.iter().next() {
+        None => break,
+        Some((&lru_clock, &lru_idx)) => (lru_clock, lru_idx),
+      };
+      self.lru.remove(&lru_clock);
+      let (_, lru_bytes, _) = self.entries.remove(&lru_idx).unwrap();
+      self.cur_bytes -= lru_bytes;
+    }
+    self.clock += 1;
+    self.cur_bytes += item_bytes;
+    self.entries.insert(idx, (self.clock, item_bytes, item));
+    self.lru.insert(self.clock, idx);
+  }
+}
+
+impl<Inner> IndexedData for CacheData<Inner> where Inner: IndexedData, Inner::Item: Clone + SizeOf {
+  type Item = Inner::Item;
+
+  fn len(&self) -> usize {
+    self.inner.len()
+  }
+
+  fn get(&mut self, idx: usize) -> Self::Item {
+    if let Some(item) = self._lookup(idx) {
+      return item;
+    }
+    let item = self.inner.get(idx);
+    self._insert(idx, item.clone());
+    item
+  }
+
+  fn try_get(&mut self, idx: usize) -> Result<Self::Item, Error> {
+    if let Some(item) = self._lookup(idx) {
+      return Ok(item);
+    }
+    let item = self.inner.try_get(idx)?;
+    self._insert(idx, item.clone());
+    Ok(item)
+  }
+}
+
+impl<Inner> Stateful for CacheData<Inner> where Inner: IndexedData + Stateful {
+  type State = Inner::State;
+
+  fn state(&mut self) -> Self::State {
+    self.inner.state()
+  }
+
+  fn restore(&mut self, state: &Self::State) {
+    self.inner.restore(state);
+  }
+}
+
+#[derive(Clone)]
+pub struct MapData<Inner, T> where Inner: IndexedData, T: Transform<Src=Inner::Item> {
+  transform:    T,
+  inner:        Inner,
+}
+
+impl<Inner, T> IndexedData for MapData<Inner, T> where Inner: IndexedData, T: Transform<Src=Inner::Item> {
+  type Item = T::Dst;
+
+  fn len(&self) -> usize {
+    self.inner.len()
+  }
+
+  fn get(&mut self, idx: usize) -> Self::Item {
+    let item = self.inner.get(idx);
+    self.transform.transform(item)
+  }
+
+  fn try_get(&mut self, idx: usize) -> Result<Self::Item, Error> {
+    let item = self.inner.try_get(idx)?;
+    Ok(self.transform.transform(item))
+  }
+}
+
+impl<Inner, T> Stateful for MapData<Inner, T> where Inner: IndexedData + Stateful, T: Transform<Src=Inner::Item> + Stateful {
+  type State = (Inner::State, T::State);
+
+  fn state(&mut self) -> Self::State {
+    (self.inner.state(), self.transform.state())
+  }
+
+  fn restore(&mut self, state: &Self::State) {
+    self.inner.restore(&state.0);
+    self.transform.restore(&state.1);
+  }
+}
+
+pub fn concat<Inner>(inners: Vec<Inner>) -> ConcatData<Inner> where Inner: IndexedData {
+  let mut offsets = Vec::with_capacity(inners.len() + 1);
+  let mut total_len = 0;
+  offsets.push(total_len);
+  for inner in inners.iter() {
+    total_len += inner.len();
+    offsets.push(total_len);
+  }
+  ConcatData{
+    offsets:  offsets,
+    inners:   inners,
+  }
+}
+
+#[derive(Clone)]
+pub struct ConcatData<Inner> where Inner: IndexedData {
+  offsets:  Vec<usize>,
+  inners:   Vec<Inner>,
+}
+
+impl<Inner> ConcatData<Inner> where Inner: IndexedData {
+  fn _locate(&self, idx: usize) -> (usize, usize) {
+    // Find the last part whose starting offset is at most `idx`; empty
+    // parts share their offset with the following part and are skipped.
+    let mut lo = 0;
+    let mut hi = self.inners.len();
+    while lo < hi {
+      let mid = (lo + hi) / 2;
+      if self.offsets[mid + 1] <= idx {
+        lo = mid + 1;
+      } else {
+        hi = mid;
+      }
+    }
+    (lo, idx - self.offsets[lo])
+  }
+}
+
+impl<Inner> IndexedData for ConcatData<Inner> where Inner: IndexedData {
+  type Item = Inner::Item;
+
+  fn len(&self) -> usize {
+    self.offsets[self.inners.len()]
+  }
+
+  fn get(&mut self, idx: usize) -> Self::Item {
+    assert!(idx < self.len());
+    let (part, part_idx) = self._locate(idx);
+    self.inners[part].get(part_idx)
+  }
+
+  fn try_get(&mut self, idx: usize) -> Result<Self::Item, Error> {
+    if idx >= self.len() {
+      return Err(Error::OutOfRange(idx, self.len()));
+    }
+    let (part, part_idx) = self._locate(idx);
+    self.inners[part].try_get(part_idx)
+  }
+}
+
+impl<Inner> Stateful for ConcatData<Inner> where Inner: IndexedData + Stateful {
+  type State = Vec<Inner::State>;
+
+  fn state(&mut self) -> Self::State {
+    let mut state = Vec::with_capacity(self.inners.len());
+    for inner in self.inners.iter_mut() {
+      state.push(inner.state());
+    }
+    state
+  }
+
+  fn restore(&mut self, state: &Self::State) {
+    assert_eq!(self.inners.len(), state.len());
+    for (inner, inner_state) in self.inners.iter_mut().zip(state.iter()) {
+      inner.restore(inner_state);
+    }
+  }
+}
+
+pub fn zip<A, B>(a: A, b: B) -> ZipData<A, B> where A: IndexedData, B: IndexedData {
+  assert_eq!(a.len(), b.len());
+  ZipData{
+    a:  a,
+    b:  b,
+  }
+}
+
+#[derive(Clone)]
+pub struct ZipData<A, B> where A: IndexedData, B: IndexedData {
+  a:  A,
+  b:  B,
+}
+
+impl<A, B> IndexedData for ZipData<A, B> where A: IndexedData, B: IndexedData {
+  type Item = (A::Item, B::Item);
+
+  fn len(&self) -> usize {
+    self.a.len()
+  }
+
+  fn get(&mut self, idx: usize) -> Self::Item {
+    (self.a.get(idx), self.b.get(idx))
+  }
+
+  fn try_get(&mut self, idx: usize) -> Result<Self::Item, Error> {
+    let a_item = self.a.try_get(idx)?;
+    let b_item = self.b.try_get(idx)?;
+    Ok((a_item, b_item))
+  }
+}
+
+impl<A, B> Stateful for ZipData<A, B> where A: IndexedData + Stateful, B: IndexedData + Stateful {
+  type State = (A::State, B::State);
+
+  fn state(&mut self) -> Self::State {
+    (self.a.state(), self.b.state())
+  }
+
+  fn restore(&mut self, state: &Self::State) {
+    self.a.restore(&state.0);
+    self.b.restore(&state.1);
+  }
 }
 
 #[derive(Clone)]
@@ -111,6 +831,19 @@ impl<Inner> Iterator for CycleData<Inner> where Inner: IndexedData {
   }
 }
 
+impl<Inner> Stateful for CycleData<Inner> where Inner: IndexedData + Stateful {
+  type State = (usize, Inner::State);
+
+  fn state(&mut self) -> Self::State {
+    (self.counter, self.inner.state())
+  }
+
+  fn restore(&mut self, state: &Self::State) {
+    self.counter = state.0;
+    self.inner.restore(&state.1);
+  }
+}
+
 #[derive(Clone)]
 pub struct RandomSampleData<Inner> where Inner: IndexedData {
   rng:      Xorshiftplus128Rng,
@@ -127,39 +860,280 @@ impl<Inner> Iterator for RandomSampleData<Inner> where Inner: IndexedData {
   }
 }
 
+impl<Inner> Stateful for RandomSampleData<Inner> where Inner: IndexedData + Stateful {
+  type State = ((u64, u64), Inner::State);
+
+  fn state(&mut self) -> Self::State {
+    (checkpoint_rng(&mut self.rng), self.inner.state())
+  }
+
+  fn restore(&mut self, state: &Self::State) {
+    restore_rng(&mut self.rng, &state.0);
+    self.inner.restore(&state.1);
+  }
+}
+
+/// Walker's alias table for drawing from a discrete distribution in O(1).
+#[derive(Clone)]
+pub struct AliasTable {
+  prob:     Vec<f64>,
+  alias:    Vec<usize>,
+}
+
+impl AliasTable {
+  pub fn new(weights: &[f64]) -> AliasTable {
+    let n = weights.len();
+    if n == 0 {
+      return AliasTable{prob: vec![], alias: vec![]};
+    }
+    let mut total = 0.0;
+    for &w in weights.iter() {
+      assert!(w >= 0.0 && w.is_finite());
+      total += w;
+    }
+    assert!(total > 0.0);
+    let mut scaled = Vec::with_capacity(n);
+    for &w in weights.iter() {
+      scaled.push(w * n as f64 / total);
+    }
+    let mut prob = vec![0.0; n];
+    let mut alias = vec![0; n];
+    let mut small = Vec::with_capacity(n);
+    let mut large = Vec::with_capacity(n);
+    for (i, &p) in scaled.iter().enumerate() {
+      if p < 1.0 {
+        small.push(i);
+      } else {
+        large.push(i);
+      }
+    }
+    while !small.is_empty() && !large.is_empty() {
+      let s = small.pop().unwrap();
+      let l = large.pop().unwrap();
+      prob[s] = scaled[s];
+      alias[s] = l;
+      scaled[l] = (scaled[l] + scaled[s]) - 1.0;
+      if scaled[l] < 1.0 {
+        small.push(l);
+      } else {
+        large.push(l);
+      }
+    }
+    // Whatever is left over is (up to rounding error) exactly at 1.
+    while let Some(l) = large.pop() {
+      prob[l] = 1.0;
+      alias[l] = l;
+    }
+    while let Some(s) = small.pop() {
+      prob[s] = 1.0;
+      alias[s] = s;
+    }
+    AliasTable{
+      prob:     prob,
+      alias:    alias,
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.prob.len()
+  }
+
+  pub fn sample<R>(&self, rng: &mut R) -> usize where R: Rng {
+    let i = rng.gen_range(0, self.prob.len());
+    if rng.next_f64() < self.prob[i] {
+      i
+    } else {
+      self.alias[i]
+    }
+  }
+}
+
+#[derive(Clone)]
+pub struct WeightedSampleData<Inner> where Inner: IndexedData {
+  table:    AliasTable,
+  rng:      Xorshiftplus128Rng,
+  inner:    Inner,
+}
+
+impl<Inner> Iterator for WeightedSampleData<Inner> where Inner: IndexedData {
+  type Item = (usize, Inner::Item);
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.table.len() == 0 {
+      return None;
+    }
+    let idx = self.table.sample(&mut self.rng);
+    let item = self.inner.get(idx);
+    Some((idx, item))
+  }
+}
+
+impl<Inner> Stateful for WeightedSampleData<Inner> where Inner: IndexedData + Stateful {
+  type State = ((u64, u64), Inner::State);
+
+  fn state(&mut self) -> Self::State {
+    (checkpoint_rng(&mut self.rng), self.inner.state())
+  }
+
+  fn restore(&mut self, state: &Self::State) {
+    restore_rng(&mut self.rng, &state.0);
+    self.inner.restore(&state.1);
+  }
+}
+
+#[derive(Clone)]
+pub struct ShuffleEpochsData<Inner> where Inner: IndexedData {
+  epoch:        usize,
+  counter:      usize,
+  perm:         Vec<usize>,
+  perm_seed:    (u64, u64),
+  rng:          Xorshiftplus128Rng,
+  inner:        Inner,
+}
+
+impl<Inner> ShuffleEpochsData<Inner> where Inner: IndexedData {
+  fn _shuffle(&mut self) {
+    // Each epoch's permutation is generated from its own seed, so that it
+    // can be regenerated when restoring from a checkpoint.
+    let mut perm_rng = Xorshiftplus128Rng::from_seed([self.perm_seed.0, self.perm_seed.1]);
+    let len = self.inner.len();
+    self.perm.clear();
+    for idx in 0 .. len {
+      self.perm.push(idx);
+    }
+    shuffle_indices(&mut self.perm, &mut perm_rng);
+  }
+}
+
+impl<Inner> Iterator for ShuffleEpochsData<Inner> where Inner: IndexedData {
+  type Item = (usize, usize, Inner::Item);
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.inner.len() == 0 {
+      return None;
+    }
+    if self.counter == 0 {
+      self.perm_seed = (self.rng.next_u64(), self.rng.next_u64());
+      self._shuffle();
+    }
+    let epoch = self.epoch;
+    let idx = self.perm[self.counter];
+    self.counter += 1;
+    if self.counter >= self.perm.len() {
+      self.counter = 0;
+      self.epoch += 1;
+    }
+    let item = self.inner.get(idx);
+    Some((epoch, idx, item))
+  }
+}
+
+#[derive(Clone, RustcEncodable, RustcDecodable)]
+pub struct ShuffleEpochsState<InnerState> {
+  pub epoch:        usize,
+  pub counter:      usize,
+  pub perm_seed:    (u64, u64),
+  pub rng:          (u64, u64),
+  pub inner:        InnerState,
+}
+
+impl<Inner> Stateful for ShuffleEpochsData<Inner> where Inner: IndexedData + Stateful {
+  type State = ShuffleEpochsState<Inner::State>;
+
+  fn state(&mut self) -> Self::State {
+    ShuffleEpochsState{
+      epoch:        self.epoch,
+      counter:      self.counter,
+      perm_seed:    self.perm_seed,
+      rng:          checkpoint_rng(&mut self.rng),
+      inner:        self.inner.state(),
+    }
+  }
+
+  fn restore(&mut self, state: &Self::State) {
+    self.inner.restore(&state.inner);
+    self.epoch = state.epoch;
+    self.counter = state.counter;
+    self.perm_seed = state.perm_seed;
+    if self.counter > 0 {
+      self._shuffle();
+    }
+    restore_rng(&mut self.rng, &state.rng);
+  }
+}
+
 pub fn async_queue<Inner>(capacity: usize, inner: Inner) -> AsyncQueue<<Inner as Iterator>::Item> where Inner: 'static + Iterator + Send, <Inner as Iterator>::Item: Send {
   let (tx, rx) = sync_channel(capacity);
-  let h = spawn(move || {
+  let cancel = Arc::new(AtomicBool::new(false));
+  let metrics = Arc::new(QueueMetrics::default());
+  let worker_cancel = cancel.clone();
+  let worker_metrics = metrics.clone();
+  let h = ThreadBuilder::new().name("async_queue".to_owned()).spawn(move || {
     let mut worker = AsyncQueueWorker{
+      cancel:   worker_cancel,
+      metrics:  worker_metrics,
       inner:    inner,
       tx:       tx,
     };
     worker._run_loop();
-  });
+  }).unwrap();
   AsyncQueue{
     capacity:   capacity,
     queue:      VecDeque::with_capacity(capacity),
-    h:          h,
-    rx:         rx,
+    cancel:     cancel,
+    metrics:    metrics,
+    h:          Some(h),
+    rx:         Some(rx),
     closed:     false,
   }
 }
 
+enum AsyncQueueMsg<Item> {
+  Item(Item),
+  Done,
+  Panic(String),
+}
+
+fn send_metered<T>(tx: &SyncSender<T>, msg: T, metrics: &QueueMetrics) -> bool {
+  let msg = match tx.try_send(msg) {
+    Ok(_) => return true,
+    Err(TrySendError::Disconnected(_)) => return false,
+    Err(TrySendError::Full(msg)) => msg,
+  };
+  metrics.record_producer_stall();
+  tx.send(msg).is_ok()
+}
+
 struct AsyncQueueWorker<Inner> where Inner: Iterator {
+  cancel:   Arc<AtomicBool>,
+  metrics:  Arc<QueueMetrics>,
   inner:    Inner,
-  tx:       SyncSender<Option<Inner::Item>>,
+  tx:       SyncSender<AsyncQueueMsg<Inner::Item>>,
 }
 
 impl<Inner> AsyncQueueWorker<Inner> where Inner: Iterator {
   pub fn _run_loop(&mut self) {
     loop {
-      match self.inner.next() {
+      if self.cancel.load(Ordering::Acquire) {
+        break;
+      }
+      let next = match catch_unwind(AssertUnwindSafe(|| self.inner.next())) {
+        Err(e) => {
+          let _ = self.tx.send(AsyncQueueMsg::Panic(panic_message(&*e)));
+          break;
+        }
+        Ok(next) => next,
+      };
+      match next {
         None => {
-          self.tx.send(None).unwrap();
+          let _ = self.tx.send(AsyncQueueMsg::Done);
           break;
         }
         Some(item) => {
-          self.tx.send(Some(item)).unwrap();
+          // The send only fails once the consumer has hung up.
+          if !send_metered(&self.tx, AsyncQueueMsg::Item(item), &self.metrics) {
+            break;
+          }
         }
       }
     }
@@ -169,11 +1143,53 @@ impl<Inner> AsyncQueueWorker<Inner> where Inner: Iterator {
 pub struct AsyncQueue<Item> {
   capacity: usize,
   queue:    VecDeque<Item>,
-  h:        JoinHandle<()>,
-  rx:       Receiver<Option<Item>>,
+  cancel:   Arc<AtomicBool>,
+  metrics:  Arc<QueueMetrics>,
+  h:        Option<JoinHandle<()>>,
+  rx:       Option<Receiver<AsyncQueueMsg<Item>>>,
   closed:   bool,
 }
 
+impl<Item> AsyncQueue<Item> {
+  pub fn metrics(&self) -> Arc<QueueMetrics> {
+    self.metrics.clone()
+  }
+
+  /// Stops the worker and waits for it to exit. Items still in flight are
+  /// discarded, and `next` returns `None` afterwards.
+  pub fn close(&mut self) {
+    self.closed = true;
+    self.queue.clear();
+    self.cancel.store(true, Ordering::Release);
+    // Dropping the receiver unblocks a worker waiting on a full channel.
+    self.rx = None;
+    if let Some(h) = self.h.take() {
+      let _ = h.join();
+    }
+  }
+
+  fn _handle(&mut self, msg: AsyncQueueMsg<Item>) {
+    match msg {
+      AsyncQueueMsg::Item(item) => {
+        self.queue.push_back(item);
+      }
+      AsyncQueueMsg::Done => {
+        self.closed = true;
+      }
+      AsyncQueueMsg::Panic(msg) => {
+        self.close();
+        panic!("async queue worker panicked: {}", msg);
+      }
+    }
+  }
+}
+
+impl<Item> Drop for AsyncQueue<Item> {
+  fn drop(&mut self) {
+    self.close();
+  }
+}
+
 impl<Item> Iterator for AsyncQueue<Item> {
   type Item = Item;
 
@@ -182,33 +1198,35 @@ impl<Item> Iterator for AsyncQueue<Item> {
       if self.closed {
         return None;
       }
-      match self.rx.recv().unwrap() {
-        None => {
-          self.closed = true;
-          return None;
-        }
-        Some(item) => {
-          self.queue.push_back(item);
+      let msg = match self.rx.as_ref().unwrap().try_recv() {
+        Ok(msg) => msg,
+        Err(TryRecvError::Disconnected) => AsyncQueueMsg::Done,
+        Err(TryRecvError::Empty) => {
+          self.metrics.record_consumer_stall();
+          match self.rx.as_ref().unwrap().recv() {
+            // The worker only hangs up without a message if it was canceled.
+            Err(_) => AsyncQueueMsg::Done,
+            Ok(msg) => msg,
+          }
         }
+      };
+      self._handle(msg);
+      if self.queue.is_empty() {
+        return None;
       }
     }
-    if !self.closed {
-      while self.queue.len() < self.capacity {
-        match self.rx.try_recv() {
-          Err(_) => {
-            break;
-          }
-          Ok(None) => {
-            self.closed = true;
-            break;
-          }
-          Ok(Some(item)) => {
-            self.queue.push_back(item);
-          }
+    while !self.closed && self.queue.len() < self.capacity {
+      let msg = match self.rx.as_ref().unwrap().try_recv() {
+        Err(_) => {
+          break;
         }
-      }
+        Ok(msg) => msg,
+      };
+      self._handle(msg);
     }
     assert!(!self.queue.is_empty());
+    self.metrics.record_occupancy(self.queue.len());
+    self.metrics.record_item();
     let item = self.queue.pop_front().unwrap();
     Some(item)
   }
@@ -255,12 +1273,14 @@ impl<Inner> Iterator for RoundRobinJoinIter<Inner> where Inner: Iterator {
 pub fn round_robin_async_split<Inner>(num_rounds: usize, capacity: usize, inner: Inner) -> Vec<Option<RoundRobinSplitConsumer<<Inner as Iterator>::Item>>> where Inner: 'static + Iterator + Send, <Inner as Iterator>::Item: Send {
   let mut txs = Vec::with_capacity(num_rounds);
   let mut consumers = Vec::with_capacity(num_rounds);
+  let metrics = Arc::new(QueueMetrics::default());
   for rank in 0 .. num_rounds {
     let (tx, rx) = sync_channel(capacity);
     txs.push(tx);
     let cons = RoundRobinSplitConsumer{
       rank:     rank,
       closed:   false,
+      metrics:  metrics.clone(),
       rx:       rx,
     };
     consumers.push(Some(cons));
@@ -268,7 +1288,9 @@ pub fn round_robin_async_split<Inner>(num_rounds: usize, capacity: usize, inner:
   let _ = spawn(move || {
     let mut producer = RoundRobinSplitProducer{
       counter:    0,
+      metrics:    metrics,
       inner:      inner,
+      hung_up:    vec![false; txs.len()],
       txs:        txs,
     };
     producer._run_loop();
@@ -278,31 +1300,51 @@ pub fn round_robin_async_split<Inner>(num_rounds: usize, capacity: usize, inner:
 
 pub struct RoundRobinSplitProducer<Inner> where Inner: Iterator {
   counter:  usize,
+  metrics:  Arc<QueueMetrics>,
   inner:    Inner,
   txs:      Vec<SyncSender<Option<Inner::Item>>>,
+  hung_up:  Vec<bool>,
 }
 
 impl<Inner> RoundRobinSplitProducer<Inner> where Inner: Iterator {
+  fn _advance(&mut self) -> bool {
+    // Move to the next rank whose consumer is still listening.
+    for _ in 0 .. self.txs.len() {
+      self.counter += 1;
+      if self.counter >= self.txs.len() {
+        self.counter = 0;
+      }
+      if !self.hung_up[self.counter] {
+        return true;
+      }
+    }
+    false
+  }
+
   pub fn _run_loop(&mut self) {
+    if self.txs.is_empty() {
+      return;
+    }
     loop {
       match self.inner.next() {
         None => {
           break;
         }
         Some(item) => {
-          self.txs[self.counter].send(Some(item)).unwrap();
-          self.counter += 1;
-          if self.counter >= self.txs.len() {
-            self.counter = 0;
+          // A dropped consumer only gives up its own share of the items;
+          // the remaining ranks keep their place in the rotation.
+          if !send_metered(&self.txs[self.counter], Some(item), &self.metrics) {
+            self.hung_up[self.counter] = true;
+          }
+          if !self._advance() {
+            return;
           }
         }
       }
     }
-    for _ in 0 .. self.txs.len() {
-      self.txs[self.counter].send(None).unwrap();
-      self.counter += 1;
-      if self.counter >= self.txs.len() {
-        self.counter = 0;
+    for rank in 0 .. self.txs.len() {
+      if !self.hung_up[rank] {
+        let _ = self.txs[rank].send(None);
       }
     }
   }
@@ -313,9 +1355,21 @@ pub struct RoundRobinSplitConsumer<Item> {
   //capacity: usize,
   closed:   bool,
   //queue:    VecDeque<Item>,
+  metrics:  Arc<QueueMetrics>,
   rx:       Receiver<Option<Item>>,
 }
 
+impl<Item> RoundRobinSplitConsumer<Item> {
+  pub fn rank(&self) -> usize {
+    self.rank
+  }
+
+  /// The metrics shared by the producer and all consumers of this split.
+  pub fn metrics(&self) -> Arc<QueueMetrics> {
+    self.metrics.clone()
+  }
+}
+
 impl<Item> Iterator for RoundRobinSplitConsumer<Item> {
   type Item = Item;
 
@@ -323,11 +1377,191 @@ impl<Item> Iterator for RoundRobinSplitConsumer<Item> {
     if self.closed {
       return None;
     }
-    // TODO
-    match self.rx.recv() {
-      Err(_) => { None }
-      Ok(None) => { None }
-      Ok(Some(item)) => { Some(item) }
+    let res = match self.rx.try_recv() {
+      Err(TryRecvError::Empty) => {
+        self.metrics.record_consumer_stall();
+        self.rx.recv().ok()
+      }
+      Err(TryRecvError::Disconnected) => None,
+      Ok(msg) => Some(msg),
+    };
+    match res {
+      None | Some(None) => {
+        self.closed = true;
+        None
+      }
+      Some(Some(item)) => {
+        self.metrics.record_item();
+        Some(item)
+      }
+    }
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SplitMode {
+  /// Deal items to consumers in strict rotation by rank; reproducible, but
+  /// the producer blocks on the slowest consumer.
+  RoundRobin,
+  /// Consumers pull from one shared bounded queue, so a slow consumer
+  /// simply takes fewer items.
+  LoadBalanced,
+}
+
+pub fn async_split<Inner>(mode: SplitMode, num_rounds: usize, capacity: usize, inner: Inner) -> Vec<Option<SplitConsumer<<Inner as Iterator>::Item>>> where Inner: 'static + Iterator + Send, <Inner as Iterator>::Item: Send {
+  match mode {
+    SplitMode::RoundRobin => {
+      round_robin_async_split(num_rounds, capacity, inner).into_iter()
+        .map(|cons| cons.map(|cons| SplitConsumer::RoundRobin(cons)))
+        .collect()
+    }
+    SplitMode::LoadBalanced => {
+      load_balanced_async_split(num_rounds, capacity, inner).into_iter()
+        .map(|cons| cons.map(|cons| SplitConsumer::LoadBalanced(cons)))
+        .collect()
     }
   }
 }
+
+pub enum SplitConsumer<Item> {
+  RoundRobin(RoundRobinSplitConsumer<Item>),
+  LoadBalanced(LoadBalancedSplitConsumer<Item>),
+}
+
+impl<Item> SplitConsumer<Item> {
+  pub fn rank(&self) -> usize {
+    match *self {
+      SplitConsumer::RoundRobin(ref cons) => cons.rank(),
+      SplitConsumer::LoadBalanced(ref cons) => cons.rank(),
+    }
+  }
+
+  pub fn metrics(&self) -> Arc<QueueMetrics> {
+    match *self {
+      SplitConsumer::RoundRobin(ref cons) => cons.metrics(),
+      SplitConsumer::LoadBalanced(ref cons) => cons.metrics(),
+    }
+  }
+}
+
+impl<Item> Iterator for SplitConsumer<Item> {
+  type Item = Item;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    match *self {
+      SplitConsumer::RoundRobin(ref mut cons) => cons.next(),
+      SplitConsumer::LoadBalanced(ref mut cons) => cons.next(),
+    }
+  }
+}
+
+pub fn load_balanced_async_split<Inner>(num_consumers: usize, capacity: usize, inner: Inner) -> Vec<Option<LoadBalancedSplitConsumer<<Inner as Iterator>::Item>>> where Inner: 'static + Iterator + Send, <Inner as Iterator>::Item: Send {
+  let (tx, rx) = sync_channel(capacity);
+  let rx = Arc::new(Mutex::new(rx));
+  let metrics = Arc::new(QueueMetrics::default());
+  let mut consumers = Vec::with_capacity(num_consumers);
+  for rank in 0 .. num_consumers {
+    let cons = LoadBalancedSplitConsumer{
+      rank:     rank,
+      closed:   false,
+      metrics:  metrics.clone(),
+      rx:       rx.clone(),
+    };
+    consumers.push(Some(cons));
+  }
+  let _ = spawn(move || {
+    let mut inner = inner;
+    loop {
+      match inner.next() {
+        None => {
+          break;
+        }
+        Some(item) => {
+          if !send_metered(&tx, item, &metrics) {
+            break;
+          }
+        }
+      }
+    }
+    // Hanging up the channel closes every consumer.
+  });
+  consumers
+}
+
+pub struct LoadBalancedSplitConsumer<Item> {
+  rank:     usize,
+  closed:   bool,
+  metrics:  Arc<QueueMetrics>,
+  rx:       Arc<Mutex<Receiver<Item>>>,
+}
+
+impl<Item> LoadBalancedSplitConsumer<Item> {
+  pub fn rank(&self) -> usize {
+    self.rank
+  }
+
+  /// The metrics shared by the producer and all consumers of this split.
+  pub fn metrics(&self) -> Arc<QueueMetrics> {
+    self.metrics.clone()
+  }
+}
+
+impl<Item> Iterator for LoadBalancedSplitConsumer<Item> {
+  type Item = Item;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.closed {
+      return None;
+    }
+    let res = {
+      let rx = self.rx.lock().unwrap();
+      match rx.try_recv() {
+        Err(TryRecvError::Empty) => {
+          self.metrics.record_consumer_stall();
+          rx.recv().ok()
+        }
+        Err(TryRecvError::Disconnected) => None,
+        Ok(item) => Some(item),
+      }
+    };
+    match res {
+      None => {
+        self.closed = true;
+        None
+      }
+      Some(item) => {
+        self.metrics.record_item();
+        Some(item)
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_alias_table_frequencies() {
+    let weights = [1.0, 0.0, 2.0, 3.0, 4.0];
+    let table = AliasTable::new(&weights);
+    assert_eq!(table.len(), weights.len());
+    let mut rng = ChaChaRng::new_unseeded();
+    let num_samples = 100000;
+    let mut counts = vec![0; weights.len()];
+    for _ in 0 .. num_samples {
+      counts[table.sample(&mut rng)] += 1;
+    }
+    assert_eq!(counts[1], 0);
+    for (&w, &count) in weights.iter().zip(counts.iter()) {
+      let freq = count as f64 / num_samples as f64;
+      assert!((freq - w / 10.0).abs() < 0.01, "weight {}: frequency {}", w, freq);
+    }
+  }
+
+  #[test]
+  fn test_alias_table_empty() {
+    let table = AliasTable::new(&[]);
+    assert_eq!(table.len(), 0);
+  }
+}
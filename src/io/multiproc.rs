@@ -0,0 +1,476 @@
+/*
+Copyright 2017 the superlearn authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+//! Data loading in forked worker processes.
+//!
+//! Each worker process runs its own `IndexedData` + `Transform` pipeline and
+//! writes finished samples into a single-producer/single-consumer ring buffer
+//! in an anonymous shared mapping; the parent reads the rings in rotation, so
+//! samples come out in the order of the given index schedule.
+//!
+//! Since `fork` only duplicates the calling thread, create the queue before
+//! spawning other threads (in particular before any `async_queue`).
+//!
+//! The rings are mapped with `libc::mmap` rather than through `sharedmem`:
+//! a `SharedMem` is either a read-only file mapping or heap storage owned by
+//! one process, and after a `fork` the child's writes to the latter land in
+//! its own copy-on-write pages, never reaching the parent. Only a
+//! `MAP_SHARED | MAP_ANONYMOUS` mapping created before the fork is writable
+//! by the workers and visible to the parent. Decoded samples are copied out
+//! of the ring into ordinary `SharedMem` buffers.
+
+use io::{IndexedData, panic_message};
+use io::transforms::{Transform};
+
+use byteorder::{ByteOrder, NativeEndian};
+use densearray::prelude::*;
+use libc;
+use sharedmem::{SharedMem};
+
+use std::cmp::{min};
+use std::marker::{PhantomData};
+use std::mem::{size_of};
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{sleep, yield_now};
+use std::time::{Duration};
+
+/// Flat encoding of samples for transfer through a ring buffer.
+pub trait RingCodec: Sized {
+  fn encoded_len(&self) -> usize;
+  fn encode(&self, buf: &mut [u8]);
+  /// Decodes a value from the front of `buf`, returning the number of bytes
+  /// consumed.
+  fn decode(buf: &[u8]) -> (Self, usize);
+}
+
+impl RingCodec for u32 {
+  fn encoded_len(&self) -> usize {
+    4
+  }
+
+  fn encode(&self, buf: &mut [u8]) {
+    NativeEndian::write_u32(&mut buf[ .. 4], *self);
+  }
+
+  fn decode(buf: &[u8]) -> (u32, usize) {
+    (NativeEndian::read_u32(&buf[ .. 4]), 4)
+  }
+}
+
+impl RingCodec for SharedMem<u8> {
+  fn encoded_len(&self) -> usize {
+    8 + self.len()
+  }
+
+  fn encode(&self, buf: &mut [u8]) {
+    NativeEndian::write_u64(&mut buf[ .. 8], self.len() as u64);
+    buf[8 .. 8 + self.len()].copy_from_slice(&*self);
+  }
+
+  fn decode(buf: &[u8]) -> (SharedMem<u8>, usize) {
+    let len = NativeEndian::read_u64(&buf[ .. 8]) as usize;
+    (SharedMem::new(buf[8 .. 8 + len].to_owned()), 8 + len)
+  }
+}
+
+impl RingCodec for Array2d<u8, SharedMem<u8>> {
+  fn encoded_len(&self) -> usize {
+    16 + self.dim().flat_len()
+  }
+
+  fn encode(&self, buf: &mut [u8]) {
+    let dim = self.dim();
+    NativeEndian::write_u64(&mut buf[0 .. 8], dim.0 as u64);
+    NativeEndian::write_u64(&mut buf[8 .. 16], dim.1 as u64);
+    buf[16 .. 16 + dim.flat_len()].copy_from_slice(self.as_slice());
+  }
+
+  fn decode(buf: &[u8]) -> (Self, usize) {
+    let dim = (
+        NativeEndian::read_u64(&buf[0 .. 8]) as usize,
+        NativeEndian::read_u64(&buf[8 .. 16]) as usize,
+    );
+    let len = dim.flat_len();
+    let data = SharedMem::new(buf[16 .. 16 + len].to_owned());
+    (Array2d::from_storage(dim, data), 16 + len)
+  }
+}
+
+impl RingCodec for Array3d<u8, SharedMem<u8>> {
+  fn encoded_len(&self) -> usize {
+    24 + self.dim().flat_len()
+  }
+
+  fn encode(&self, buf: &mut [u8]) {
+    let dim = self.dim();
+    NativeEndian::write_u64(&mut buf[0 .. 8], dim.0 as u64);
+    NativeEndian::write_u64(&mut buf[8 .. 16], dim.1 as u64);
+    NativeEndian::write_u64(&mut buf[16 .. 24], dim.2 as u64);
+    buf[24 .. 24 + dim.flat_len()].copy_from_slice(self.as_slice());
+  }
+
+  fn decode(buf: &[u8]) -> (Self, usize) {
+    let dim = (
+        NativeEndian::read_u64(&buf[0 .. 8]) as usize,
+        NativeEndian::read_u64(&buf[8 .. 16]) as usize,
+        NativeEndian::read_u64(&buf[16 .. 24]) as usize,
+    );
+    let len = dim.flat_len();
+    let data = SharedMem::new(buf[24 .. 24 + len].to_owned());
+    (Array3d::from_storage(dim, data), 24 + len)
+  }
+}
+
+impl RingCodec for Array3d<f32, SharedMem<f32>> {
+  fn encoded_len(&self) -> usize {
+    24 + 4 * self.dim().flat_len()
+  }
+
+  fn encode(&self, buf: &mut [u8]) {
+    let dim = self.dim();
+    NativeEndian::write_u64(&mut buf[0 .. 8], dim.0 as u64);
+    NativeEndian::write_u64(&mut buf[8 .. 16], dim.1 as u64);
+    NativeEndian::write_u64(&mut buf[16 .. 24], dim.2 as u64);
+    for (i, &x) in self.as_slice().iter().enumerate() {
+      NativeEndian::write_f32(&mut buf[24 + 4 * i .. 24 + 4 * (i + 1)], x);
+    }
+  }
+
+  fn decode(buf: &[u8]) -> (Self, usize) {
+    let dim = (
+        NativeEndian::read_u64(&buf[0 .. 8]) as usize,
+        NativeEndian::read_u64(&buf[8 .. 16]) as usize,
+        NativeEndian::read_u64(&buf[16 .. 24]) as usize,
+    );
+    let len = dim.flat_len();
+    let mut data = Vec::with_capacity(len);
+    for i in 0 .. len {
+      data.push(NativeEndian::read_f32(&buf[24 + 4 * i .. 24 + 4 * (i + 1)]));
+    }
+    (Array3d::from_storage(dim, SharedMem::new(data)), 24 + 4 * len)
+  }
+}
+
+impl<A, B> RingCodec for (A, B) where A: RingCodec, B: RingCodec {
+  fn encoded_len(&self) -> usize {
+    self.0.encoded_len() + self.1.encoded_len()
+  }
+
+  fn encode(&self, buf: &mut [u8]) {
+    let a_len = self.0.encoded_len();
+    self.0.encode(&mut buf[ .. a_len]);
+    self.1.encode(&mut buf[a_len .. ]);
+  }
+
+  fn decode(buf: &[u8]) -> ((A, B), usize) {
+    let (a, a_len) = A::decode(buf);
+    let (b, b_len) = B::decode(&buf[a_len .. ]);
+    ((a, b), a_len + b_len)
+  }
+}
+
+// Ring header layout; the counters sit on separate cache lines.
+const HEAD_OFFSET:      isize = 0;
+const TAIL_OFFSET:      isize = 64;
+const STATUS_OFFSET:    isize = 128;
+const MSG_LEN_OFFSET:   isize = 192;
+const MSG_OFFSET:       isize = 256;
+const MSG_CAPACITY:     usize = 256;
+const HEADER_SIZE:      usize = 512;
+
+const STATUS_RUNNING:   usize = 0;
+const STATUS_DONE:      usize = 1;
+const STATUS_PANICKED:  usize = 2;
+
+const SKIP_MARKER:      u64 = 0xffff_ffff_ffff_ffff;
+
+fn pad8(len: usize) -> usize {
+  (len + 7) / 8 * 8
+}
+
+fn backoff(spins: &mut usize) {
+  *spins += 1;
+  if *spins < 64 {
+    yield_now();
+  } else {
+    sleep(Duration::new(0, 100_000));
+  }
+}
+
+/// A view of one ring: a header followed by `capacity` bytes of records.
+/// `head` and `tail` are running byte counts of records written and read.
+struct Ring {
+  base:     *mut u8,
+  capacity: usize,
+}
+
+impl Ring {
+  fn head(&self) -> &AtomicUsize {
+    unsafe { &*(self.base.offset(HEAD_OFFSET) as *const AtomicUsize) }
+  }
+
+  fn tail(&self) -> &AtomicUsize {
+    unsafe { &*(self.base.offset(TAIL_OFFSET) as *const AtomicUsize) }
+  }
+
+  fn status(&self) -> &AtomicUsize {
+    unsafe { &*(self.base.offset(STATUS_OFFSET) as *const AtomicUsize) }
+  }
+
+  fn data(&self) -> *mut u8 {
+    unsafe { self.base.offset(HEADER_SIZE as isize) }
+  }
+
+  fn set_panicked(&self, msg: &str) {
+    let msg = msg.as_bytes();
+    let len = min(msg.len(), MSG_CAPACITY);
+    unsafe {
+      ptr::copy_nonoverlapping(msg.as_ptr(), self.base.offset(MSG_OFFSET), len);
+      ptr::write(self.base.offset(MSG_LEN_OFFSET) as *mut usize, len);
+    }
+    self.status().store(STATUS_PANICKED, Ordering::Release);
+  }
+
+  fn panic_message(&self) -> String {
+    unsafe {
+      let len = ptr::read(self.base.offset(MSG_LEN_OFFSET) as *const usize);
+      let msg = slice::from_raw_parts(self.base.offset(MSG_OFFSET), min(len, MSG_CAPACITY));
+      String::from_utf8_lossy(msg).into_owned()
+    }
+  }
+
+  fn push<Item>(&self, item: &Item) where Item: RingCodec {
+    let len = item.encoded_len();
+    let rec_sz = 8 + pad8(len);
+    assert!(rec_sz <= self.capacity,
+        "multiproc: encoded item of {} bytes does not fit in a ring of {} bytes", len, self.capacity);
+    let mut head = self.head().load(Ordering::Relaxed);
+    let mut spins = 0;
+    loop {
+      let pos = head % self.capacity;
+      // Records never wrap; if this one would, skip to the start of the ring.
+      let skip = if pos + rec_sz > self.capacity { self.capacity - pos } else { 0 };
+      let tail = self.tail().load(Ordering::Acquire);
+      let need = if skip > 0 { skip } else { rec_sz };
+      if head - tail + need > self.capacity {
+        backoff(&mut spins);
+        continue;
+      }
+      unsafe {
+        if skip > 0 {
+          ptr::write(self.data().offset(pos as isize) as *mut u64, SKIP_MARKER);
+          head += skip;
+          self.head().store(head, Ordering::Release);
+          continue;
+        }
+        let rec = self.data().offset(pos as isize);
+        ptr::write(rec as *mut u64, len as u64);
+        item.encode(slice::from_raw_parts_mut(rec.offset(8), len));
+      }
+      self.head().store(head + rec_sz, Ordering::Release);
+      return;
+    }
+  }
+
+  /// Returns `Ok(None)` if the ring is currently empty, and `Err(status)`
+  /// if it is empty and the writer has stopped.
+  fn try_pop<Item>(&self) -> Result<Option<Item>, usize> where Item: RingCodec {
+    let mut tail = self.tail().load(Ordering::Relaxed);
+    loop {
+      let head = self.head().load(Ordering::Acquire);
+      if tail == head {
+        let status = self.status().load(Ordering::Acquire);
+        if status != STATUS_RUNNING && self.head().load(Ordering::Acquire) == tail {
+          return Err(status);
+        }
+        return Ok(None);
+      }
+      let pos = tail % self.capacity;
+      unsafe {
+        let rec = self.data().offset(pos as isize);
+        let len = ptr::read(rec as *const u64);
+        if len == SKIP_MARKER {
+          tail += self.capacity - pos;
+          self.tail().store(tail, Ordering::Release);
+          continue;
+        }
+        let len = len as usize;
+        let (item, _) = Item::decode(slice::from_raw_parts(rec.offset(8), len));
+        self.tail().store(tail + 8 + pad8(len), Ordering::Release);
+        return Ok(Some(item));
+      }
+    }
+  }
+}
+
+/// Forks `num_workers` processes that each apply a transform (built by
+/// `transform_factory` from the worker rank) to `data` at the positions of
+/// `indices` assigned to them, and returns the samples in `indices` order.
+///
+/// `ring_bytes` is the capacity of each worker's ring buffer and must exceed
+/// the encoded size of the largest sample.
+pub fn multiproc_queue<Data, T, F>(num_workers: usize, ring_bytes: usize, data: Data, indices: Vec<usize>, transform_factory: F) -> MultiprocQueue<T::Dst>
+where Data: IndexedData,
+      T: Transform<Src=Data::Item>,
+      T::Dst: RingCodec,
+      F: Fn(usize) -> T,
+{
+  assert!(num_workers >= 1);
+  let capacity = pad8(ring_bytes);
+  let ring_stride = HEADER_SIZE + capacity;
+  let map_len = num_workers * ring_stride;
+  let map_ptr = unsafe { libc::mmap(
+      ptr::null_mut(), map_len,
+      libc::PROT_READ | libc::PROT_WRITE,
+      libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+      -1, 0,
+  ) };
+  if map_ptr == libc::MAP_FAILED {
+    panic!("multiproc: failed to mmap {} bytes of ring buffers", map_len);
+  }
+  // Anonymous mappings are zero-filled, which is the initial ring state.
+  let map_ptr = map_ptr as *mut u8;
+  let mut rings = Vec::with_capacity(num_workers);
+  for rank in 0 .. num_workers {
+    rings.push(Ring{
+      base:     unsafe { map_ptr.offset((rank * ring_stride) as isize) },
+      capacity: capacity,
+    });
+  }
+  let mut data = data;
+  let mut pids = Vec::with_capacity(num_workers);
+  for rank in 0 .. num_workers {
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+      panic!("multiproc: fork failed");
+    }
+    if pid == 0 {
+      let ring = &rings[rank];
+      let res = catch_unwind(AssertUnwindSafe(|| {
+        let mut transform = transform_factory(rank);
+        let mut p = rank;
+        while p < indices.len() {
+          let item = data.get(indices[p]);
+          let dst = transform.transform(item);
+          ring.push(&dst);
+          p += num_workers;
+        }
+      }));
+      match res {
+        Err(e) => ring.set_panicked(&panic_message(&*e)),
+        Ok(_) => ring.status().store(STATUS_DONE, Ordering::Release),
+      }
+      unsafe { libc::_exit(0) };
+    }
+    pids.push(Some(pid));
+  }
+  MultiprocQueue{
+    counter:  0,
+    closed:   false,
+    rings:    rings,
+    pids:     pids,
+    map_ptr:  map_ptr,
+    map_len:  map_len,
+    _marker:  PhantomData,
+  }
+}
+
+pub struct MultiprocQueue<Item> {
+  counter:  usize,
+  closed:   bool,
+  rings:    Vec<Ring>,
+  pids:     Vec<Option<libc::pid_t>>,
+  map_ptr:  *mut u8,
+  map_len:  usize,
+  _marker:  PhantomData<fn () -> Item>,
+}
+
+impl<Item> MultiprocQueue<Item> {
+  fn _check_alive(&mut self, rank: usize) {
+    if let Some(pid) = self.pids[rank] {
+      let mut status = 0;
+      let ret = unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) };
+      if ret == pid {
+        self.pids[rank] = None;
+        // The worker may have finished between our last look at the ring
+        // and reaping it.
+        if self.rings[rank].status().load(Ordering::Acquire) == STATUS_RUNNING {
+          self.closed = true;
+          panic!("multiproc: worker {} exited unexpectedly (status: {})", rank, status);
+        }
+      }
+    }
+  }
+
+  pub fn close(&mut self) {
+    self.closed = true;
+    for pid in self.pids.iter_mut() {
+      if let Some(p) = pid.take() {
+        let mut status = 0;
+        unsafe {
+          libc::kill(p, libc::SIGKILL);
+          libc::waitpid(p, &mut status, 0);
+        }
+      }
+    }
+  }
+}
+
+impl<Item> Drop for MultiprocQueue<Item> {
+  fn drop(&mut self) {
+    self.close();
+    unsafe { libc::munmap(self.map_ptr as *mut libc::c_void, self.map_len) };
+  }
+}
+
+impl<Item> Iterator for MultiprocQueue<Item> where Item: RingCodec {
+  type Item = Item;
+
+  fn next(&mut self) -> Option<Item> {
+    if self.closed {
+      return None;
+    }
+    let rank = self.counter % self.rings.len();
+    let mut spins = 0;
+    loop {
+      match self.rings[rank].try_pop() {
+        Ok(Some(item)) => {
+          self.counter += 1;
+          return Some(item);
+        }
+        Ok(None) => {
+          if spins % 1024 == 1023 {
+            self._check_alive(rank);
+          }
+          backoff(&mut spins);
+        }
+        Err(STATUS_PANICKED) => {
+          let msg = self.rings[rank].panic_message();
+          self.close();
+          panic!("multiproc: worker {} panicked: {}", rank, msg);
+        }
+        Err(_) => {
+          // The schedule position owned by this worker is past the end.
+          self.close();
+          return None;
+        }
+      }
+    }
+  }
+}
@@ -0,0 +1,188 @@
+/*
+Copyright 2017 the superlearn authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use io::{panic_message};
+use io::transforms::{Transform};
+
+use std::collections::{BTreeMap};
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Sender, Receiver, channel};
+use std::thread::{Builder as ThreadBuilder};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MapOrder {
+  /// Yield results in the order their inputs were pulled from the inner
+  /// iterator.
+  Submission,
+  /// Yield results as soon as any worker finishes them.
+  Completion,
+}
+
+/// Applies a transform to the items of `inner` on `num_workers` threads.
+///
+/// Each worker builds its own transform (and so its own RNG) by calling
+/// `transform_factory` with its rank. At most `capacity` items are in flight
+/// (dispatched but not yet yielded) at any time.
+pub fn parallel_map<Inner, T, F>(num_workers: usize, capacity: usize, order: MapOrder, inner: Inner, transform_factory: F) -> ParallelMap<T::Dst>
+where Inner: 'static + Iterator + Send,
+      <Inner as Iterator>::Item: 'static + Send,
+      T: Transform<Src=<Inner as Iterator>::Item>,
+      T::Dst: 'static + Send,
+      F: 'static + Fn(usize) -> T + Send + Sync,
+{
+  assert!(num_workers >= 1);
+  assert!(capacity >= 1);
+  let (credit_tx, credit_rx) = channel();
+  for _ in 0 .. capacity {
+    credit_tx.send(()).unwrap();
+  }
+  let (job_tx, job_rx) = channel();
+  let job_rx = Arc::new(Mutex::new(job_rx));
+  let (result_tx, result_rx) = channel();
+  let dispatch_result_tx: Sender<(usize, Result<T::Dst, String>)> = result_tx.clone();
+  let _ = ThreadBuilder::new().name("parallel_map-dispatch".to_owned()).spawn(move || {
+    let mut inner = inner;
+    let mut seq = 0;
+    loop {
+      if credit_rx.recv().is_err() {
+        break;
+      }
+      // A panicking source must not look like the end of the data.
+      match catch_unwind(AssertUnwindSafe(|| inner.next())) {
+        Err(e) => {
+          let _ = dispatch_result_tx.send((seq, Err(format!("source panicked: {}", panic_message(&*e)))));
+          break;
+        }
+        Ok(None) => {
+          break;
+        }
+        Ok(Some(item)) => {
+          if job_tx.send((seq, item)).is_err() {
+            break;
+          }
+          seq += 1;
+        }
+      }
+    }
+  }).unwrap();
+  let transform_factory = Arc::new(transform_factory);
+  for rank in 0 .. num_workers {
+    let job_rx: Arc<Mutex<Receiver<(usize, <Inner as Iterator>::Item)>>> = job_rx.clone();
+    let result_tx: Sender<(usize, Result<T::Dst, String>)> = result_tx.clone();
+    let transform_factory = transform_factory.clone();
+    let _ = ThreadBuilder::new().name(format!("parallel_map-{}", rank)).spawn(move || {
+      let mut transform = (*transform_factory)(rank);
+      loop {
+        let job = {
+          let job_rx = job_rx.lock().unwrap();
+          job_rx.recv()
+        };
+        match job {
+          Err(_) => {
+            break;
+          }
+          Ok((seq, item)) => {
+            match catch_unwind(AssertUnwindSafe(|| transform.transform(item))) {
+              Err(e) => {
+                let _ = result_tx.send((seq, Err(format!("worker panicked: {}", panic_message(&*e)))));
+                break;
+              }
+              Ok(dst) => {
+                if result_tx.send((seq, Ok(dst))).is_err() {
+                  break;
+                }
+              }
+            }
+          }
+        }
+      }
+    }).unwrap();
+  }
+  ParallelMap{
+    order:      order,
+    next_seq:   0,
+    pending:    BTreeMap::new(),
+    credit_tx:  credit_tx,
+    rx:         result_rx,
+    closed:     false,
+  }
+}
+
+pub struct ParallelMap<Item> {
+  order:        MapOrder,
+  next_seq:     usize,
+  pending:      BTreeMap<usize, Item>,
+  credit_tx:    Sender<()>,
+  rx:           Receiver<(usize, Result<Item, String>)>,
+  closed:       bool,
+}
+
+impl<Item> ParallelMap<Item> {
+  fn _recv(&mut self) -> Option<(usize, Item)> {
+    match self.rx.recv() {
+      Err(_) => {
+        self.closed = true;
+        None
+      }
+      Ok((_, Err(msg))) => {
+        self.closed = true;
+        panic!("parallel_map: {}", msg);
+      }
+      Ok((seq, Ok(item))) => {
+        Some((seq, item))
+      }
+    }
+  }
+}
+
+impl<Item> Iterator for ParallelMap<Item> {
+  type Item = Item;
+
+  fn next(&mut self) -> Option<Item> {
+    if self.closed {
+      return None;
+    }
+    let item = match self.order {
+      MapOrder::Completion => {
+        match self._recv() {
+          None => return None,
+          Some((_, item)) => item,
+        }
+      }
+      MapOrder::Submission => {
+        loop {
+          let next_seq = self.next_seq;
+          if let Some(item) = self.pending.remove(&next_seq) {
+            self.next_seq += 1;
+            break item;
+          }
+          match self._recv() {
+            None => {
+              assert!(self.pending.is_empty());
+              return None;
+            }
+            Some((seq, item)) => {
+              self.pending.insert(seq, item);
+            }
+          }
+        }
+      }
+    };
+    let _ = self.credit_tx.send(());
+    Some(item)
+  }
+}
@@ -0,0 +1,175 @@
+/*
+Copyright 2017 the superlearn authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use io::{AsyncQueueMsg, SizeOf, panic_message};
+use io::metrics::{QueueMetrics};
+
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, TryRecvError, channel};
+use std::thread::{Builder as ThreadBuilder, JoinHandle};
+
+struct Budget {
+  bytes:    usize,
+  count:    usize,
+  closed:   bool,
+}
+
+/// Like `async_queue`, but bounds the prefetched items by their total size
+/// in bytes (as given by `SizeOf`) rather than by their number. A single item
+/// larger than `max_bytes` is still let through once the queue is empty.
+pub fn prefetch_bytes<Inner>(max_bytes: usize, inner: Inner) -> PrefetchQueue<<Inner as Iterator>::Item> where Inner: 'static + Iterator + Send, <Inner as Iterator>::Item: Send + SizeOf {
+  let (tx, rx) = channel();
+  let budget = Arc::new((Mutex::new(Budget{bytes: 0, count: 0, closed: false}), Condvar::new()));
+  let cancel = Arc::new(AtomicBool::new(false));
+  let metrics = Arc::new(QueueMetrics::default());
+  let worker_budget = budget.clone();
+  let worker_cancel = cancel.clone();
+  let worker_metrics = metrics.clone();
+  let h = ThreadBuilder::new().name("prefetch_bytes".to_owned()).spawn(move || {
+    let mut inner = inner;
+    loop {
+      if worker_cancel.load(Ordering::Acquire) {
+        break;
+      }
+      let next = match catch_unwind(AssertUnwindSafe(|| inner.next())) {
+        Err(e) => {
+          let _ = tx.send(AsyncQueueMsg::Panic(panic_message(&*e)));
+          break;
+        }
+        Ok(next) => next,
+      };
+      let item = match next {
+        None => {
+          let _ = tx.send(AsyncQueueMsg::Done);
+          break;
+        }
+        Some(item) => item,
+      };
+      let item_bytes = item.size_of();
+      {
+        let &(ref lock, ref cvar) = &*worker_budget;
+        let mut budget = lock.lock().unwrap();
+        if !budget.closed && budget.count > 0 && budget.bytes + item_bytes > max_bytes {
+          worker_metrics.record_producer_stall();
+        }
+        while !budget.closed && budget.count > 0 && budget.bytes + item_bytes > max_bytes {
+          budget = cvar.wait(budget).unwrap();
+        }
+        if budget.closed {
+          break;
+        }
+        budget.bytes += item_bytes;
+        budget.count += 1;
+      }
+      if tx.send(AsyncQueueMsg::Item((item, item_bytes))).is_err() {
+        break;
+      }
+    }
+  }).unwrap();
+  PrefetchQueue{
+    budget:     budget,
+    cancel:     cancel,
+    metrics:    metrics,
+    h:          Some(h),
+    rx:         Some(rx),
+    closed:     false,
+  }
+}
+
+pub struct PrefetchQueue<Item> {
+  budget:   Arc<(Mutex<Budget>, Condvar)>,
+  cancel:   Arc<AtomicBool>,
+  metrics:  Arc<QueueMetrics>,
+  h:        Option<JoinHandle<()>>,
+  rx:       Option<Receiver<AsyncQueueMsg<(Item, usize)>>>,
+  closed:   bool,
+}
+
+impl<Item> PrefetchQueue<Item> {
+  pub fn metrics(&self) -> Arc<QueueMetrics> {
+    self.metrics.clone()
+  }
+
+  /// The total size of the items currently held by the queue.
+  pub fn bytes_in_flight(&self) -> usize {
+    let &(ref lock, _) = &*self.budget;
+    lock.lock().unwrap().bytes
+  }
+
+  /// Stops the worker and waits for it to exit.
+  pub fn close(&mut self) {
+    self.closed = true;
+    self.cancel.store(true, Ordering::Release);
+    {
+      let &(ref lock, ref cvar) = &*self.budget;
+      lock.lock().unwrap().closed = true;
+      cvar.notify_all();
+    }
+    self.rx = None;
+    if let Some(h) = self.h.take() {
+      let _ = h.join();
+    }
+  }
+}
+
+impl<Item> Drop for PrefetchQueue<Item> {
+  fn drop(&mut self) {
+    self.close();
+  }
+}
+
+impl<Item> Iterator for PrefetchQueue<Item> {
+  type Item = Item;
+
+  fn next(&mut self) -> Option<Item> {
+    if self.closed {
+      return None;
+    }
+    let msg = match self.rx.as_ref().unwrap().try_recv() {
+      Ok(msg) => msg,
+      Err(TryRecvError::Disconnected) => AsyncQueueMsg::Done,
+      Err(TryRecvError::Empty) => {
+        self.metrics.record_consumer_stall();
+        match self.rx.as_ref().unwrap().recv() {
+          Err(_) => AsyncQueueMsg::Done,
+          Ok(msg) => msg,
+        }
+      }
+    };
+    match msg {
+      AsyncQueueMsg::Item((item, item_bytes)) => {
+        let &(ref lock, ref cvar) = &*self.budget;
+        let mut budget = lock.lock().unwrap();
+        self.metrics.record_occupancy(budget.count);
+        self.metrics.record_item();
+        budget.bytes -= item_bytes;
+        budget.count -= 1;
+        cvar.notify_one();
+        Some(item)
+      }
+      AsyncQueueMsg::Done => {
+        self.closed = true;
+        None
+      }
+      AsyncQueueMsg::Panic(msg) => {
+        self.close();
+        panic!("prefetch worker panicked: {}", msg);
+      }
+    }
+  }
+}
@@ -0,0 +1,109 @@
+/*
+Copyright 2017 the superlearn authors
+
+Licensed under the Apache License, Version 2.0 (the "License");
+you may not use this file except in compliance with the License.
+You may obtain a copy of the License at
+
+    http://www.apache.org/licenses/LICENSE-2.0
+
+Unless required by applicable law or agreed to in writing, software
+distributed under the License is distributed on an "AS IS" BASIS,
+WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+See the License for the specific language governing permissions and
+limitations under the License.
+*/
+
+use io::{Error};
+
+use rng::xorshift::*;
+
+use rand::{Rng, SeedableRng};
+use rustc_serialize::{Encodable, Decodable};
+use rustc_serialize::json;
+use std::fs::{File};
+use std::io::{Read, Write};
+use std::path::{Path};
+
+/// Save/restore of the position and RNG state of a data pipeline, so that a
+/// restarted job sees exactly the same sequence of items.
+///
+/// Calling `state` reseeds every live RNG in the pipeline (see
+/// `checkpoint_rng`), which is why it takes `&mut self`. A run that takes
+/// checkpoints therefore sees a different item order from an otherwise
+/// identical run that never does; only a run restored from a checkpoint is
+/// guaranteed to continue exactly as the run that took it.
+///
+/// Pipelines that contain background threads (e.g. `async_queue`) should be
+/// checkpointed on the inner, synchronous side.
+pub trait Stateful {
+  type State: Clone + Encodable + Decodable;
+
+  fn state(&mut self) -> Self::State;
+  fn restore(&mut self, state: &Self::State);
+}
+
+/// Implements `Stateful` with an empty state, for sources and transforms
+/// that have no position or RNG of their own.
+macro_rules! impl_stateless {
+  ($name:ident) => {
+    impl $crate::io::state::Stateful for $name {
+      type State = ();
+
+      fn state(&mut self) {
+      }
+
+      fn restore(&mut self, _state: &()) {
+      }
+    }
+  };
+  ($name:ident<$($param:ident),+>) => {
+    impl<$($param),+> $crate::io::state::Stateful for $name<$($param),+> {
+      type State = ();
+
+      fn state(&mut self) {
+      }
+
+      fn restore(&mut self, _state: &()) {
+      }
+    }
+  };
+}
+
+/// Draws a fresh seed from `rng` and reseeds `rng` with it, returning the
+/// seed. Restoring the seed later with `restore_rng` puts an RNG into exactly
+/// the same state as `rng` is in after this call.
+pub fn checkpoint_rng(rng: &mut Xorshiftplus128Rng) -> (u64, u64) {
+  let seed = [rng.next_u64(), rng.next_u64()];
+  rng.reseed(seed);
+  (seed[0], seed[1])
+}
+
+pub fn restore_rng(rng: &mut Xorshiftplus128Rng, state: &(u64, u64)) {
+  rng.reseed([state.0, state.1]);
+}
+
+/// Writes the state of `pipeline` to `path` as JSON.
+pub fn save_state<S>(pipeline: &mut S, path: &Path) -> Result<(), Error> where S: Stateful {
+  let state = pipeline.state();
+  let encoded = match json::encode(&state) {
+    Ok(encoded) => encoded,
+    Err(e) => return Err(Error::Format(format!("failed to encode pipeline state: {:?}", e))),
+  };
+  let mut file = File::create(path)?;
+  file.write_all(encoded.as_bytes())?;
+  Ok(())
+}
+
+/// Restores the state of `pipeline` from a JSON file written by `save_state`.
+pub fn load_state<S>(pipeline: &mut S, path: &Path) -> Result<(), Error> where S: Stateful {
+  let mut file = File::open(path)?;
+  let mut encoded = String::new();
+  file.read_to_string(&mut encoded)?;
+  let state: S::State = match json::decode(&encoded) {
+    Ok(state) => state,
+    Err(e) => return Err(Error::Format(format!("failed to decode pipeline state: {:?}", e))),
+  };
+  pipeline.restore(&state);
+  Ok(())
+}
@@ -1,4 +1,5 @@
 use super::{Transform};
+use io::state::{Stateful, checkpoint_rng, restore_rng};
 
 use densearray::prelude::*;
 use sharedmem::*;
@@ -41,6 +42,8 @@ impl Transform for ImageCast<f32, u8> {
   }
 }
 
+impl_stateless!(ImageCast<T, U>);
+
 pub struct PlanarImageZeroPad {
   pad_w:    usize,
   pad_h:    usize,
@@ -79,6 +82,8 @@ impl Transform for PlanarImageZeroPad {
   }
 }
 
+impl_stateless!(PlanarImageZeroPad);
+
 pub struct PlanarImageAddColorNoise {
   rng:      Xorshiftplus128Rng,
 }
@@ -140,6 +145,18 @@ impl Transform for PlanarImageAddPixelPCANoise {
   }
 }
 
+impl Stateful for PlanarImageAddPixelPCANoise {
+  type State = (u64, u64);
+
+  fn state(&mut self) -> (u64, u64) {
+    checkpoint_rng(&mut self.rng)
+  }
+
+  fn restore(&mut self, state: &(u64, u64)) {
+    restore_rng(&mut self.rng, state);
+  }
+}
+
 pub struct PlanarImageLinearResize<T> {
   src_dim:  (usize, usize),
   dst_dim:  (usize, usize),
@@ -181,6 +198,8 @@ impl Transform for PlanarImageLinearResize<f32> {
   }
 }
 
+impl_stateless!(PlanarImageLinearResize<T>);
+
 #[derive(Default)]
 pub struct ImageTranspose;
 
@@ -208,6 +227,8 @@ impl Transform for ImageTranspose {
   }
 }
 
+impl_stateless!(ImageTranspose);
+
 pub struct ImageRandomRescale {
   lo_side:  usize,
   hi_side:  usize,
@@ -314,6 +335,18 @@ impl Transform for ImageRandomRescale {
   }
 }
 
+impl Stateful for ImageRandomRescale {
+  type State = (u64, u64);
+
+  fn state(&mut self) -> (u64, u64) {
+    checkpoint_rng(&mut self.rng)
+  }
+
+  fn restore(&mut self, state: &(u64, u64)) {
+    restore_rng(&mut self.rng, state);
+  }
+}
+
 pub struct PlanarImageRandomRescaleF32 {
   lo_side:  usize,
   hi_side:  usize,
@@ -413,6 +446,18 @@ impl Transform for PlanarImageRandomRescaleF32 {
   }
 }
 
+impl Stateful for PlanarImageRandomRescaleF32 {
+  type State = (u64, u64);
+
+  fn state(&mut self) -> (u64, u64) {
+    checkpoint_rng(&mut self.rng)
+  }
+
+  fn restore(&mut self, state: &(u64, u64)) {
+    restore_rng(&mut self.rng, state);
+  }
+}
+
 pub struct ImageRandomCrop {
   crop_w:   usize,
   crop_h:   usize,
@@ -462,6 +507,18 @@ impl Transform for ImageRandomCrop {
   }
 }
 
+impl Stateful for ImageRandomCrop {
+  type State = (u64, u64);
+
+  fn state(&mut self) -> (u64, u64) {
+    checkpoint_rng(&mut self.rng)
+  }
+
+  fn restore(&mut self, state: &(u64, u64)) {
+    restore_rng(&mut self.rng, state);
+  }
+}
+
 pub struct ImageCenterCrop {
   crop_w:   usize,
   crop_h:   usize,
@@ -509,6 +566,8 @@ impl Transform for ImageCenterCrop {
   }
 }
 
+impl_stateless!(ImageCenterCrop);
+
 pub struct ImageRandomFlipX {
   rng:  Xorshiftplus128Rng,
 }
@@ -549,3 +608,15 @@ impl Transform for ImageRandomFlipX {
     Array3d::from_storage(src.dim(), SharedMem::new(buf))
   }
 }
+
+impl Stateful for ImageRandomFlipX {
+  type State = (u64, u64);
+
+  fn state(&mut self) -> (u64, u64) {
+    checkpoint_rng(&mut self.rng)
+  }
+
+  fn restore(&mut self, state: &(u64, u64)) {
+    restore_rng(&mut self.rng, state);
+  }
+}
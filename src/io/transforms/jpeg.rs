@@ -88,3 +88,5 @@ impl Transform for JpegDecoder {
     Ok(Array3d::from_storage((3, width, height), SharedMem::new(pixels)))
   }
 }
+
+impl_stateless!(JpegDecoder);
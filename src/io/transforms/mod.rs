@@ -1,9 +1,13 @@
+use io::state::{Stateful};
+
 use byteorder::*;
 use densearray::prelude::*;
 use sharedmem::*;
 
 use rand::{Rng, SeedableRng};
+use std::fmt::{Debug};
 use std::io::{Cursor};
+use std::marker::{PhantomData};
 
 pub mod image;
 pub mod jpeg;
@@ -21,6 +25,76 @@ pub fn default_decoder<Dec>() -> impl FnMut(Dec::Src) -> Dec::Dst where Dec: Dec
   move |src: Dec::Src| decoder.decode(src)
 }*/
 
+/// Applies the inner transform to the first element of a `(data, label)`
+/// pair and passes the label through, e.g. to decode labeled JPEG buffers.
+pub struct Labeled<T, Label> {
+  inner:    T,
+  _marker:  PhantomData<fn (Label)>,
+}
+
+impl<T, Label> Labeled<T, Label> {
+  pub fn new(inner: T) -> Self {
+    Labeled{
+      inner:    inner,
+      _marker:  PhantomData,
+    }
+  }
+}
+
+impl<T, Label> Default for Labeled<T, Label> where T: Default {
+  fn default() -> Self {
+    Labeled::new(T::default())
+  }
+}
+
+impl<T, Label> Transform for Labeled<T, Label> where T: Transform {
+  type Src = (T::Src, Label);
+  type Dst = (T::Dst, Label);
+
+  fn transform(&mut self, src: (T::Src, Label)) -> (T::Dst, Label) {
+    let (data, label) = src;
+    (self.inner.transform(data), label)
+  }
+}
+
+impl<T, Label> Stateful for Labeled<T, Label> where T: Stateful {
+  type State = T::State;
+
+  fn state(&mut self) -> T::State {
+    self.inner.state()
+  }
+
+  fn restore(&mut self, state: &T::State) {
+    self.inner.restore(state);
+  }
+}
+
+/// Unwraps the result of a fallible transform such as `JpegDecoder`, so that
+/// it can be chained with infallible ones.
+pub struct Unwrap<T, E> {
+  _marker:  PhantomData<fn (T, E)>,
+}
+
+impl<T, E> Default for Unwrap<T, E> {
+  fn default() -> Self {
+    Unwrap{_marker: PhantomData}
+  }
+}
+
+impl<T, E> Transform for Unwrap<T, E> where E: Debug {
+  type Src = Result<T, E>;
+  type Dst = T;
+
+  fn transform(&mut self, src: Result<T, E>) -> T {
+    match src {
+      Err(e) => panic!("transform failed: {:?}", e),
+      Ok(x) => x,
+    }
+  }
+}
+
+impl_stateless!(Unwrap<T, E>);
+
 #[derive(Default)]
 pub struct LabelSuffixDecoder;
 
@@ -39,3 +113,5 @@ impl Transform for LabelSuffixDecoder {
     (src.slice_v2( .. buf_len - 4), label)
   }
 }
+
+impl_stateless!(LabelSuffixDecoder);
@@ -21,7 +21,9 @@ extern crate byteorder;
 extern crate csv;
 extern crate densearray;
 extern crate extar;
+extern crate flate2;
 extern crate ipp;
+extern crate libc;
 extern crate rng;
 extern crate sharedmem;
 extern crate stb_image;
@@ -36,4 +38,4 @@ extern crate rustc_serialize;
 pub mod io;
 //pub mod opt;
 pub mod templates;
-//pub mod util;
+pub mod util;